@@ -0,0 +1,228 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use tracing::{error, info, warn};
+use crate::http;
+use crate::http::{Request, Response};
+use crate::self_check::EngineStatus;
+use crate::settings::Settings;
+use crate::storage::Storage;
+use crate::template_cache;
+
+/// Serves the admin API on the given address
+///
+/// Read-only:
+/// - `GET /jobs`: queued rendering requests in rendering order & running ones with their current export steps
+/// - `GET /templates`: cached template versions of all namespaces
+/// - `GET /health`: self-check results of the rendering engines & current load
+///
+/// Actions:
+/// - `POST /jobs/<request id>/cancel`: removes a queued request or stops a running one before its next export step
+/// - `POST /drain`: finishes queued & running requests, then shuts down
+/// - `POST /cache/purge[?namespace=<namespace>]`: removes cached templates not used by queued or running requests
+///
+/// Actions require `admin_token` as bearer token. Requests with an Origin header or a Host other than the address are refused,
+/// so websites opened in a browser on the same machine can't reach the API.
+pub async fn serve(address: SocketAddr, storage: Arc<Storage>, settings: Arc<Settings>){
+    let listener = match TcpListener::bind(address).await{
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't listen for the admin API on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving admin API on http://{}/.", address);
+
+    http::serve(listener, move |request| {
+        let storage = storage.clone();
+        let settings = settings.clone();
+        async move{ handle_request(request, address, &storage, &settings) }
+    }).await
+}
+
+fn handle_request(request: Request, address: SocketAddr, storage: &Storage, settings: &Settings) -> Response{
+    if let Err(response) = check_access(&request, address, settings){
+        warn!(method = %request.method, path = %request.path, "Refused admin API request.");
+        return response;
+    }
+
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()){
+        ("GET", ["jobs"]) => json_response(jobs(storage)),
+        ("GET", ["templates"]) => json_response(templates(storage)),
+        ("GET", ["health"]) => json_response(health(storage, settings)),
+        ("POST", ["jobs", request_id, "cancel"]) => {
+            let request_id = match uuid::Uuid::parse_str(request_id){
+                Ok(request_id) => request_id,
+                Err(_) => return error_response("400 Bad Request", "Invalid request id.")
+            };
+            if storage.cancel(request_id){
+                warn!(request_id = %request_id, "Rendering request cancelled by operator.");
+                json_response(json!({ "cancelled": request_id.to_string() }))
+            }else{
+                error_response("404 Not Found", "No queued or running rendering request with this id.")
+            }
+        },
+        ("POST", ["drain"]) => {
+            warn!("Drain requested via admin API.");
+            storage.request_drain();
+            json_response(json!({ "draining": true }))
+        },
+        ("POST", ["cache", "purge"]) => {
+            let evicted = template_cache::evict(storage, settings, request.query_param("namespace"));
+            info!(evicted, "Purged template cache via admin API.");
+            json_response(json!({ "evicted_templates": evicted }))
+        },
+        (_, ["jobs"] | ["templates"] | ["health"] | ["jobs", _, "cancel"] | ["drain"] | ["cache", "purge"]) => error_response("405 Method Not Allowed", "Method not allowed."),
+        _ => error_response("404 Not Found", "Not found.")
+    }
+}
+
+/// Refuses requests sent by browsers & actions without the admin token
+fn check_access(request: &Request, address: SocketAddr, settings: &Settings) -> Result<(), Response>{
+    // Browsers send an Origin header with cross-origin requests
+    if request.header("Origin").is_some(){
+        return Err(error_response("403 Forbidden", "Cross-origin requests are not allowed."));
+    }
+    // DNS rebinding sends the Host of the attacker's domain
+    let host = request.header("Host").unwrap_or_default();
+    if host != address.to_string() && host != format!("localhost:{}", address.port()){
+        return Err(error_response("403 Forbidden", "Host doesn't match the admin address."));
+    }
+    if request.method == "POST"{
+        let token = request.header("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        match &settings.admin_token{
+            Some(admin_token) if token == Some(admin_token.as_str()) => {},
+            Some(_) => return Err(error_response("401 Unauthorized", "Actions require the admin token as bearer token.")),
+            None => return Err(error_response("403 Forbidden", "Actions are disabled, admin_token isn't set."))
+        }
+    }
+    Ok(())
+}
+
+fn jobs(storage: &Storage) -> Value{
+    let queued: Vec<Value> = storage.request_queue.read().unwrap().iter().enumerate().map(|(position, queued)| json!({
+        "request_id": queued.request.request_id.to_string(),
+        "client": queued.client.client_name,
+        "template_id": queued.request.template_id.to_string(),
+        "export_formats": queued.request.export_formats,
+        "position": position,
+    })).collect();
+
+    let running: Vec<Value> = storage.running_requests.read().unwrap().iter().map(|(request_id, running)| json!({
+        "request_id": request_id.to_string(),
+        "client": running.client,
+        "template_id": running.template_id.to_string(),
+        "export_formats": running.export_formats,
+        "elapsed_seconds": running.started.elapsed().as_secs_f64(),
+        "current_steps": *running.current_steps.lock().unwrap(),
+        "cancelled": running.cancelled.load(Ordering::Relaxed),
+    })).collect();
+
+    json!({ "queued": queued, "running": running })
+}
+
+fn templates(storage: &Storage) -> Value{
+    let templates: Vec<Value> = storage.template_storage.read().unwrap().iter().map(|((namespace, template_id), entry)| json!({
+        "namespace": namespace,
+        "template_id": template_id.to_string(),
+        "current_version_id": entry.version_id.to_string(),
+        "cached_version_ids": entry.cached_versions.iter().map(|version| version.manifest.template_version_id.to_string()).collect::<Vec<_>>(),
+        "export_formats": entry.export_formats(entry.version_id).map(|export_formats| export_formats.keys().collect::<Vec<_>>()).unwrap_or_default(),
+    })).collect();
+
+    json!({ "templates": templates })
+}
+
+fn health(storage: &Storage, settings: &Settings) -> Value{
+    let environment = storage.environment.read().unwrap();
+    let engine = |status: &EngineStatus| json!({
+        "available": status.available,
+        "version": status.version,
+        "error": status.error,
+    });
+
+    json!({
+        "bwrap_version": environment.bwrap_version,
+        "font_dir": environment.font_dir,
+        "engines": {
+            "vivliostyle": engine(&environment.vivliostyle),
+            "pandoc": engine(&environment.pandoc),
+        },
+        "draining": storage.is_draining(),
+        "queued_requests": storage.request_queue.read().unwrap().len(),
+        "running_requests": storage.running_jobs.load(Ordering::Relaxed),
+        "max_rendering_threads": settings.max_rendering_threads.get(),
+    })
+}
+
+fn json_response(value: Value) -> Response{
+    Response::new("200 OK", "application/json", value.to_string())
+}
+
+fn error_response(status: &'static str, message: &str) -> Response{
+    Response::new(status, "application/json", json!({ "error": message }).to_string())
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const TOKEN: &str = "0123456789abcdef";
+
+    fn address() -> SocketAddr{
+        "127.0.0.1:9101".parse().unwrap()
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request{
+        Request{
+            method: method.to_string(),
+            path: "/drain".to_string(),
+            query: None,
+            headers: headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+        }
+    }
+
+    fn settings(admin_token: Option<&str>) -> Settings{
+        let mut settings = Settings::new(None).unwrap();
+        settings.admin_token = admin_token.map(str::to_string);
+        settings
+    }
+
+    fn status(res: Result<(), Response>) -> Option<&'static str>{
+        res.err().map(|response| response.status)
+    }
+
+    #[test]
+    fn reads_need_only_a_matching_host(){
+        let settings = settings(None);
+        assert_eq!(status(check_access(&request("GET", &[("Host", "127.0.0.1:9101")]), address(), &settings)), None);
+        assert_eq!(status(check_access(&request("GET", &[("host", "localhost:9101")]), address(), &settings)), None);
+        assert_eq!(status(check_access(&request("GET", &[("Host", "evil.example:9101")]), address(), &settings)), Some("403 Forbidden"));
+        assert_eq!(status(check_access(&request("GET", &[]), address(), &settings)), Some("403 Forbidden"));
+    }
+
+    #[test]
+    fn refuses_cross_origin_requests(){
+        let settings = settings(Some(TOKEN));
+        let bearer = format!("Bearer {}", TOKEN);
+        let headers = [("Host", "127.0.0.1:9101"), ("Authorization", bearer.as_str()), ("Origin", "http://127.0.0.1:9101")];
+        assert_eq!(status(check_access(&request("POST", &headers), address(), &settings)), Some("403 Forbidden"));
+    }
+
+    #[test]
+    fn actions_require_the_admin_token(){
+        let bearer = format!("Bearer {}", TOKEN);
+        let with_token = request("POST", &[("Host", "127.0.0.1:9101"), ("Authorization", bearer.as_str())]);
+        let wrong_token = request("POST", &[("Host", "127.0.0.1:9101"), ("Authorization", "Bearer guessed")]);
+        let without_token = request("POST", &[("Host", "127.0.0.1:9101")]);
+
+        assert_eq!(status(check_access(&with_token, address(), &settings(Some(TOKEN)))), None);
+        assert_eq!(status(check_access(&wrong_token, address(), &settings(Some(TOKEN)))), Some("401 Unauthorized"));
+        assert_eq!(status(check_access(&without_token, address(), &settings(Some(TOKEN)))), Some("401 Unauthorized"));
+        // Disabled without a configured token
+        assert_eq!(status(check_access(&with_token, address(), &settings(None))), Some("403 Forbidden"));
+    }
+}
@@ -0,0 +1,185 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+use vb_exchange::RejectionReason;
+use crate::quotas::JobSlot;
+use crate::settings::Settings;
+use crate::storage::Storage;
+
+/// Weight of the latest rendering request in the average duration
+const SMOOTHING: f64 = 0.2;
+/// Seconds a busy main server is told to wait while no rendering request has finished yet
+const FALLBACK_RETRY_AFTER: u64 = 30;
+
+/// Moving average of the duration of rendering requests, used to estimate waiting times
+#[derive(Default)]
+pub struct JobDurationEstimate{
+    average: Mutex<Option<Duration>>,
+}
+
+impl JobDurationEstimate{
+    pub fn record(&self, duration: Duration){
+        let mut average = self.average.lock().unwrap();
+        *average = Some(match *average{
+            Some(average) => average.mul_f64(1.0 - SMOOTHING) + duration.mul_f64(SMOOTHING),
+            None => duration,
+        });
+    }
+
+    pub fn average(&self) -> Option<Duration>{
+        *self.average.lock().unwrap()
+    }
+}
+
+/// Estimated time for the given number of queued rendering requests to be started
+fn estimated_wait(storage: &Storage, settings: &Settings, queued: u64) -> Option<Duration>{
+    let average = storage.job_durations.average()?;
+    let threads = settings.max_rendering_threads.get();
+    let running = storage.running_jobs.load(Ordering::Relaxed);
+
+    // Rounds of rendering before a thread is free for the last of them
+    let rounds = (queued + running) / threads;
+    Some(average.mul_f64(rounds as f64))
+}
+
+/// Place of an admitted rendering request in the backlog until it's queued, released when dropped
+pub struct QueueReservation{
+    pending_requests: Arc<AtomicU64>,
+}
+
+impl Drop for QueueReservation{
+    fn drop(&mut self){
+        self.pending_requests.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Admitted rendering request, counted as concurrent job of the client & in the backlog until it's queued
+pub struct Admission{
+    pub slot: JobSlot,
+    pub reservation: QueueReservation,
+}
+
+/// Number of queued rendering requests, including admitted ones still fetching their template or saving uploads
+fn backlog(storage: &Storage) -> u64{
+    storage.request_queue.read().unwrap().len() as u64 + storage.pending_requests.load(Ordering::Relaxed)
+}
+
+/// Estimated time until a new rendering request would be finished
+pub fn estimated_completion(storage: &Storage, settings: &Settings) -> Option<Duration>{
+    completion_behind(storage, settings, backlog(storage))
+}
+
+fn completion_behind(storage: &Storage, settings: &Settings, queued: u64) -> Option<Duration>{
+    Some(estimated_wait(storage, settings, queued)? + storage.job_durations.average()?)
+}
+
+/// Rejects a new rendering request if the queue is full or it can't be finished before the deadline (in seconds) of the main server
+///
+/// Otherwise reserves a place in the backlog for the request until it's queued.
+pub fn check_backlog(storage: &Storage, settings: &Settings, deadline: Option<u64>) -> Result<QueueReservation, RejectionReason>{
+    // Reserved before counting, so concurrent requests can't take the same last place
+    storage.pending_requests.fetch_add(1, Ordering::Relaxed);
+    let reservation = QueueReservation{ pending_requests: storage.pending_requests.clone() };
+    let queued = backlog(storage) - 1;
+
+    if queued >= settings.max_queue_depth{
+        // Wait until enough queued requests got started
+        let retry_after = estimated_wait(storage, settings, queued - settings.max_queue_depth)
+            .map(|wait| wait.as_secs().max(1))
+            .unwrap_or(FALLBACK_RETRY_AFTER);
+        info!("Rejected rendering request: {} requests queued, retry after {}s.", queued, retry_after);
+        return Err(RejectionReason::Busy{ retry_after });
+    }
+
+    if let (Some(deadline), Some(completion)) = (deadline, completion_behind(storage, settings, queued)){
+        let deadline = Duration::from_secs(deadline);
+        if completion > deadline{
+            let retry_after = (completion - deadline).as_secs().max(1);
+            info!("Rejected rendering request: estimated completion in {}s exceeds deadline of {}s.", completion.as_secs(), deadline.as_secs());
+            return Err(RejectionReason::Busy{ retry_after });
+        }
+    }
+
+    Ok(reservation)
+}
+
+#[cfg(test)]
+mod tests{
+    use std::num::NonZeroU64;
+    use super::*;
+
+    fn settings(max_rendering_threads: u64, max_queue_depth: u64) -> Settings{
+        let mut settings = Settings::new(None).unwrap();
+        settings.max_rendering_threads = NonZeroU64::new(max_rendering_threads).unwrap();
+        settings.max_queue_depth = max_queue_depth;
+        settings
+    }
+
+    #[test]
+    fn estimate_starts_with_first_duration(){
+        let estimate = JobDurationEstimate::default();
+        assert_eq!(estimate.average(), None);
+        estimate.record(Duration::from_secs(10));
+        assert_eq!(estimate.average(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn estimate_smooths_later_durations(){
+        let estimate = JobDurationEstimate::default();
+        estimate.record(Duration::from_secs(10));
+        estimate.record(Duration::from_secs(20));
+        assert_eq!(estimate.average(), Some(Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn completion_counts_rounds_of_rendering(){
+        let storage = Storage::new();
+        let settings = settings(2, 100);
+        assert_eq!(estimated_completion(&storage, &settings), None);
+
+        storage.job_durations.record(Duration::from_secs(10));
+        assert_eq!(estimated_completion(&storage, &settings), Some(Duration::from_secs(10)));
+        storage.running_jobs.store(1, Ordering::Relaxed);
+        assert_eq!(estimated_completion(&storage, &settings), Some(Duration::from_secs(10)));
+        storage.running_jobs.store(2, Ordering::Relaxed);
+        assert_eq!(estimated_completion(&storage, &settings), Some(Duration::from_secs(20)));
+    }
+
+    #[test]
+    fn rejects_full_queue(){
+        let storage = Storage::new();
+        assert!(check_backlog(&storage, &settings(1, 1), None).is_ok());
+        assert!(matches!(check_backlog(&storage, &settings(1, 0), None), Err(RejectionReason::Busy{ retry_after: FALLBACK_RETRY_AFTER })));
+    }
+
+    #[test]
+    fn rejects_deadlines_that_cant_be_met(){
+        let storage = Storage::new();
+        let settings = settings(1, 100);
+        // Nothing finished yet, so there's no estimate to reject with
+        assert!(check_backlog(&storage, &settings, Some(1)).is_ok());
+
+        storage.job_durations.record(Duration::from_secs(10));
+        storage.running_jobs.store(2, Ordering::Relaxed);
+        assert!(check_backlog(&storage, &settings, None).is_ok());
+        assert!(check_backlog(&storage, &settings, Some(30)).is_ok());
+        assert!(matches!(check_backlog(&storage, &settings, Some(25)), Err(RejectionReason::Busy{ retry_after: 5 })));
+    }
+
+    #[test]
+    fn counts_admitted_requests_until_queued(){
+        let storage = Storage::new();
+        let settings = settings(1, 2);
+
+        let first = check_backlog(&storage, &settings, None).unwrap();
+        let second = check_backlog(&storage, &settings, None).unwrap();
+        assert!(check_backlog(&storage, &settings, None).is_err());
+        assert_eq!(storage.pending_requests.load(Ordering::Relaxed), 2);
+
+        drop(first);
+        assert!(check_backlog(&storage, &settings, None).is_ok());
+        drop(second);
+        assert_eq!(storage.pending_requests.load(Ordering::Relaxed), 0);
+    }
+}
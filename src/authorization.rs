@@ -0,0 +1,185 @@
+use std::fmt::{Display, Formatter};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::Deserialize;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tracing::{error, warn};
+use x509_parser::extensions::GeneralName;
+use crate::quotas::QuotaLimits;
+use crate::settings::Settings;
+
+/// Template namespace of clients without an own namespace
+pub const DEFAULT_TEMPLATE_NAMESPACE: &str = "default";
+
+/// Authorization policy of one main server (Verfassungsbooks instance)
+#[derive(Debug, Deserialize, Clone)]
+pub struct ClientPolicy{
+    /// Name of the instance, used in logs
+    pub name: String,
+    /// Common names or DNS SANs of the certificates used by the instance
+    pub identities: Vec<String>,
+    /// Allow rendering requests and template uploads. Capability & cache queries are always allowed
+    #[serde(default = "default_true")]
+    pub may_render: bool,
+    /// Allow draining this rendering server, denied unless set
+    #[serde(default)]
+    pub may_drain: bool,
+    /// Requests with a higher priority are rendered first
+    #[serde(default)]
+    pub priority: i32,
+    /// Namespace the templates of the instance are cached in. Instances sharing a namespace share their templates.
+    /// Defaults to the name of the instance
+    pub template_namespace: Option<String>,
+    /// Max queued & running rendering requests
+    pub max_concurrent_jobs: Option<u64>,
+    pub max_jobs_per_hour: Option<u64>,
+    /// Max bytes of uploaded project files per hour
+    pub max_upload_bytes_per_hour: Option<u64>,
+    /// Max render time per hour in seconds, summed over all export formats
+    pub max_render_seconds_per_hour: Option<u64>,
+}
+
+fn default_true() -> bool{
+    true
+}
+
+/// Identity of a connected main server, taken from its certificate
+#[derive(Debug, Clone, Default)]
+pub struct ClientIdentity{
+    pub common_name: Option<String>,
+    pub dns_names: Vec<String>,
+}
+
+impl ClientIdentity{
+    /// Reads common name & DNS SANs from the end-entity certificate
+    pub fn from_certificate(cert: &CertificateDer) -> Result<ClientIdentity, String>{
+        let (_, cert) = x509_parser::parse_x509_certificate(cert.as_ref()).map_err(|e| format!("Couldn't parse certificate: {}", e))?;
+
+        let common_name = cert.subject().iter_common_name().next().and_then(|cn| cn.as_str().ok()).map(|cn| cn.to_string());
+        let mut dns_names = Vec::new();
+        if let Ok(Some(san)) = cert.subject_alternative_name(){
+            for name in &san.value.general_names{
+                if let GeneralName::DNSName(dns_name) = name{
+                    dns_names.push(dns_name.to_string());
+                }
+            }
+        }
+
+        Ok(ClientIdentity{ common_name, dns_names })
+    }
+
+    /// Identity of the peer of a TLS connection, empty if it didn't send a certificate
+    pub fn from_peer_certificates(certs: Option<&[CertificateDer]>) -> Result<ClientIdentity, String>{
+        match certs.and_then(|certs| certs.first()){
+            Some(cert) => ClientIdentity::from_certificate(cert),
+            None => Ok(ClientIdentity::default())
+        }
+    }
+
+    fn matches(&self, identity: &str) -> bool{
+        self.common_name.as_deref() == Some(identity) || self.dns_names.iter().any(|name| name == identity)
+    }
+}
+
+impl Display for ClientIdentity{
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result{
+        write!(f, "CN={}", self.common_name.as_deref().unwrap_or("-"))?;
+        if !self.dns_names.is_empty(){
+            write!(f, " SAN={}", self.dns_names.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Permissions of a connected main server, resolved from its policy
+#[derive(Debug, Clone)]
+pub struct Authorization{
+    /// Name of the matching policy, or the identity of unlisted clients
+    pub client_name: String,
+    pub identity: ClientIdentity,
+    pub may_render: bool,
+    pub may_drain: bool,
+    pub priority: i32,
+    pub template_namespace: String,
+    pub limits: QuotaLimits,
+}
+
+/// Actions restricted by the client policies
+#[derive(Debug, Clone, Copy)]
+pub enum Action{
+    Render,
+    UploadTemplate,
+    Drain,
+}
+
+impl Authorization{
+    /// Checks if the client may do the action, denials are written to the audit log
+    pub fn permits(&self, settings: &Settings, action: Action) -> bool{
+        let (permitted, name) = match action{
+            Action::Render => (self.may_render, "rendering request"),
+            Action::UploadTemplate => (self.may_render, "template upload"),
+            Action::Drain => (self.may_drain, "drain"),
+        };
+
+        if !permitted{
+            audit_denial(settings, &self.identity, name, &format!("not permitted by policy {}", self.client_name));
+        }
+        permitted
+    }
+}
+
+/// Looks up the policy of a client. Fails if no policy matches and allow_unlisted_clients is disabled
+pub fn authorize(settings: &Settings, identity: ClientIdentity) -> Result<Authorization, String>{
+    match settings.clients.iter().find(|policy| policy.identities.iter().any(|i| identity.matches(i))){
+        Some(policy) => Ok(Authorization{
+            client_name: policy.name.clone(),
+            identity,
+            may_render: policy.may_render,
+            may_drain: policy.may_drain,
+            priority: policy.priority,
+            template_namespace: policy.template_namespace.clone().unwrap_or_else(|| policy.name.clone()),
+            limits: QuotaLimits{
+                max_concurrent_jobs: policy.max_concurrent_jobs,
+                max_jobs_per_hour: policy.max_jobs_per_hour,
+                max_upload_bytes_per_hour: policy.max_upload_bytes_per_hour,
+                max_render_seconds_per_hour: policy.max_render_seconds_per_hour,
+            },
+        }),
+        None => {
+            if settings.allow_unlisted_clients{
+                Ok(Authorization{
+                    client_name: identity.to_string(),
+                    identity,
+                    may_render: true,
+                    // Shutting down the server is left to listed clients
+                    may_drain: false,
+                    priority: 0,
+                    template_namespace: DEFAULT_TEMPLATE_NAMESPACE.to_string(),
+                    limits: QuotaLimits::default(),
+                })
+            }else{
+                Err(format!("No policy for {}", identity))
+            }
+        }
+    }
+}
+
+/// Logs a denied action and, if configured, appends it to the audit log
+pub fn audit_denial(settings: &Settings, identity: &ClientIdentity, action: &str, reason: &str){
+    warn!(action, identity = %identity, "Denied: {}", reason);
+
+    if let Some(path) = &settings.audit_log_path{
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let res = OpenOptions::new().create(true).append(true).open(path)
+            .and_then(|mut file| writeln!(file, "{}\tdenied\t{}\t{}\t{}", timestamp, identity, action, reason));
+        if let Err(e) = res{
+            error!("Couldn't write to audit log {}: {}", path, e);
+        }
+    }
+}
+
+/// Namespaces are used as directory names, so only allow a safe subset of characters
+pub fn is_valid_namespace(namespace: &str) -> bool{
+    !namespace.is_empty() && namespace.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
@@ -0,0 +1,36 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use vb_exchange::RenderingServerCapabilities;
+use crate::settings::Settings;
+use crate::storage::Storage;
+
+/// Collects the export step types & engine versions this server supports, its limits and its current load
+///
+/// Engines that failed the startup self-check are left out.
+pub fn collect_capabilities(storage: &Storage, settings: &Settings) -> RenderingServerCapabilities{
+    let mut step_types = vec!["raw".to_string()];
+    let mut engine_versions = HashMap::new();
+
+    {
+        let environment = storage.environment.read().unwrap();
+        for (name, status) in [("vivliostyle", &environment.vivliostyle), ("pandoc", &environment.pandoc)]{
+            if status.available{
+                step_types.push(name.to_string());
+                if let Some(version) = &status.version{
+                    engine_versions.insert(name.to_string(), version.clone());
+                }
+            }
+        }
+    }
+
+    RenderingServerCapabilities{
+        server_version: env!("CARGO_PKG_VERSION").to_string(),
+        step_types,
+        engine_versions,
+        max_concurrent_jobs: settings.max_rendering_threads.get(),
+        max_upload_bytes: Some(settings.max_upload_bytes),
+        queued_jobs: storage.request_queue.read().unwrap().len() as u64,
+        running_jobs: storage.running_jobs.load(Ordering::Relaxed),
+        draining: storage.is_draining(),
+    }
+}
@@ -0,0 +1,159 @@
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use crate::settings::Settings;
+
+/// Rendering server for Verfassungsbooks instances
+#[derive(Debug, Parser)]
+#[command(about, disable_version_flag = true)]
+pub struct Cli{
+    /// Config file to read on top of config/default, instead of config/<RUN_MODE> & config/local. APP_* environment variables still apply
+    #[arg(long, global = true)]
+    pub config: Option<PathBuf>,
+    /// Prints the version of the server & of the rendering engines
+    #[arg(short = 'V', long)]
+    pub version: bool,
+    /// Runs `serve` if no command is given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command{
+    /// Listens for main servers & renders their requests
+    Serve(ServeArgs),
+    /// Validates the config and runs the self-check of the sandbox & rendering environments
+    CheckEnv,
+    /// Inspects the template versions saved in the temp template dir
+    Cache{
+        #[command(subcommand)]
+        command: CacheCommand,
+    },
+    /// Renders a local template & project without a main server
+    Render(RenderArgs),
+    /// Checks a template for layout, handlebars & export step problems
+    LintTemplate(LintArgs),
+    /// Re-renders a local template & project on every change and serves the result with auto-refresh
+    Dev(DevArgs),
+}
+
+/// Overrides of the settings for `serve`
+#[derive(Debug, Default, Args)]
+pub struct ServeArgs{
+    /// Host to listen on, overrides bind_to_host
+    #[arg(long)]
+    pub bind: Option<String>,
+    /// Port to listen on, overrides port
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Overrides max_rendering_threads
+    #[arg(long)]
+    pub max_rendering_threads: Option<NonZeroU64>,
+    /// Overrides max_queue_depth
+    #[arg(long)]
+    pub max_queue_depth: Option<u64>,
+}
+
+impl ServeArgs{
+    pub fn apply(&self, settings: &mut Settings){
+        if let Some(bind) = &self.bind{
+            settings.bind_to_host = bind.clone();
+        }
+        if let Some(port) = self.port{
+            settings.port = port;
+        }
+        if let Some(max_rendering_threads) = self.max_rendering_threads{
+            settings.max_rendering_threads = max_rendering_threads;
+        }
+        if let Some(max_queue_depth) = self.max_queue_depth{
+            settings.max_queue_depth = max_queue_depth;
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum CacheCommand{
+    /// Lists the saved template versions with their size
+    List,
+    /// Removes saved template versions. Stop servers using the same temp template dir first
+    Purge{
+        /// Only removes the versions of this template namespace
+        #[arg(long)]
+        namespace: Option<String>,
+    },
+}
+
+#[derive(Debug, Args)]
+pub struct RenderArgs{
+    /// Template directory with assets/ and formats/<slug>
+    #[arg(long)]
+    pub template: PathBuf,
+    /// JSON file with the export formats of the template, defaults to export_formats.json inside the template directory
+    #[arg(long)]
+    pub export_formats: Option<PathBuf>,
+    /// JSON file with the PreparedProject to render
+    #[arg(long)]
+    pub project: PathBuf,
+    /// Directory with the uploaded project files
+    #[arg(long)]
+    pub uploads: Option<PathBuf>,
+    /// Directory to write the rendered files & logs to, one subdirectory per export format
+    #[arg(long, short)]
+    pub output: PathBuf,
+    /// Keeps the workspaces of failed export formats in <output>/failed
+    #[arg(long)]
+    pub keep_failed: bool,
+    /// Slugs of the export formats to render
+    #[arg(required = true)]
+    pub formats: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct LintArgs{
+    /// Template directory with assets/ and formats/<slug>
+    pub template: PathBuf,
+    /// JSON file with the export formats of the template, defaults to export_formats.json inside the template directory
+    #[arg(long)]
+    pub export_formats: Option<PathBuf>,
+    /// Output format of the diagnostics
+    #[arg(long, value_enum, default_value = "text")]
+    pub output: LintOutput,
+    /// Fails on warnings, too
+    #[arg(long)]
+    pub deny_warnings: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct DevArgs{
+    /// Template directory with assets/ and formats/<slug>
+    #[arg(long)]
+    pub template: PathBuf,
+    /// JSON file with the export formats of the template, defaults to export_formats.json inside the template directory
+    #[arg(long)]
+    pub export_formats: Option<PathBuf>,
+    /// JSON file with the PreparedProject to render
+    #[arg(long)]
+    pub project: PathBuf,
+    /// Directory with the uploaded project files
+    #[arg(long)]
+    pub uploads: Option<PathBuf>,
+    /// Host to serve the preview on
+    #[arg(long, default_value = "127.0.0.1")]
+    pub bind: String,
+    /// Port to serve the preview on
+    #[arg(long, default_value_t = 8080)]
+    pub port: u16,
+    /// Also runs the Vivliostyle step following the Raw step, the PDF is served at /__dev/pdf
+    #[arg(long)]
+    pub vivliostyle: bool,
+    /// Slug of the export format to preview
+    pub format: String,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum LintOutput{
+    /// One `file:line:column: severity[code]: message` per line
+    Text,
+    /// JSON array of diagnostics
+    Json,
+}
@@ -0,0 +1,87 @@
+use std::io;
+use crate::cli::CacheCommand;
+use crate::self_check;
+use crate::settings::{self, Settings};
+use crate::storage;
+use crate::template_cache;
+use crate::tls::TlsConfigs;
+
+/// Prints the version of the server and, if the config could be read, of bubblewrap & the rendering engines
+pub fn print_version(settings: Option<&Settings>){
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    match settings{
+        Some(settings) => {
+            for (name, version) in self_check::engine_versions(settings){
+                println!("{}: {}", name, version.as_deref().unwrap_or("unavailable"));
+            }
+        },
+        None => println!("Engine versions unknown, couldn't read config.")
+    }
+}
+
+/// Validates the config, certificates & rendering environments like `serve` does on startup, returns whether all checks passed
+pub fn check_env(settings: &Settings) -> bool{
+    let mut ok = true;
+
+    if let Err(problems) = settings.validate(){
+        for problem in problems{
+            eprintln!("Invalid config: {}", problem);
+        }
+        ok = false;
+    }
+    if let Err(e) = TlsConfigs::load(settings){
+        eprintln!("{}. Check Certs & Key!", e);
+        ok = false;
+    }
+
+    // The self-check renders inside the job work dir
+    let mut settings = settings.clone();
+    let work_dir = std::env::temp_dir().join(format!("vb-check-env-{}", uuid::Uuid::new_v4()));
+    if let Err(e) = std::fs::create_dir(&work_dir){
+        eprintln!("Couldn't create directory for the self-check: {}", e);
+        return false;
+    }
+    settings.job_work_path = work_dir.to_string_lossy().to_string();
+
+    println!("Checking rendering environments...");
+    let environment = self_check::check_environment(&settings);
+    let _ = std::fs::remove_dir_all(&work_dir);
+    println!("bwrap: {}, vivliostyle: {}, pandoc: {}", environment.bwrap_version.as_deref().unwrap_or("missing"), environment.vivliostyle.version.as_deref().unwrap_or("unavailable"), environment.pandoc.version.as_deref().unwrap_or("unavailable"));
+    for problem in environment.problems(){
+        eprintln!("Self-check failed: {}", problem);
+        ok = false;
+    }
+
+    if ok{
+        println!("All checks passed.");
+    }
+    ok
+}
+
+/// Lists or purges the template versions saved in the temp template dir
+///
+/// Refused while a server uses the dir, it would still consider purged versions cached. Its admin API lists & purges them instead.
+pub fn cache(settings: &Settings, command: &CacheCommand) -> Result<(), String>{
+    settings.validate_working_dirs().map_err(|problems| settings::describe_problems(&problems))?;
+    let _lock = storage::lock_working_dirs(settings).map_err(|e| match e.kind(){
+        io::ErrorKind::WouldBlock => format!("{}. Use its admin API (GET /templates, POST /cache/purge) or stop it first.", e),
+        _ => format!("Couldn't lock {}: {}", settings.temp_template_path, e)
+    })?;
+
+    match command{
+        CacheCommand::List => {
+            let versions = template_cache::versions_on_disk(settings).map_err(|e| format!("Couldn't read {}: {}", settings.temp_template_path, e))?;
+            if versions.is_empty(){
+                println!("No template versions saved in {}.", settings.temp_template_path);
+            }
+            for version in versions{
+                println!("{}\t{}\t{} bytes", version.namespace, version.template_version_id, version.bytes);
+            }
+        },
+        CacheCommand::Purge{ namespace } => {
+            let removed = template_cache::purge(settings, namespace.as_deref()).map_err(|e| format!("Couldn't purge {}: {}", settings.temp_template_path, e))?;
+            println!("Removed {} template versions.", removed);
+        }
+    }
+    Ok(())
+}
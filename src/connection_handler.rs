@@ -1,107 +1,200 @@
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpStream;
 use tokio_rustls::TlsStream;
-use vb_exchange::{CommunicationError, FilesOnMemoryOrHarddrive, Message, RenderingError, RenderingStatus, TemplateDataRequest};
+use tracing::{error, info, warn, Span};
+use vb_exchange::{CachedTemplatesResult, CommunicationError, FilesOnMemoryOrHarddrive, Message, RenderingError, RejectionReason, RenderingRequest, RenderingStatus, RetainedWorkspace, TemplateDataRequest, TemplateDataResult, TemplateDeltaRequest, TemplateVersionManifest};
+use vb_exchange::export_formats::ExportFormat;
+use crate::admission;
+use crate::admission::Admission;
+use crate::authorization;
+use crate::authorization::{audit_denial, Action, Authorization, ClientIdentity};
+use crate::capabilities::collect_capabilities;
+use crate::limits;
+use crate::limits::{LimitedReader, TreeLimits};
+use crate::protocol;
+use crate::protocol::NegotiatedProtocol;
+use crate::retention;
+use crate::safe_path;
+use crate::session;
 use crate::settings::Settings;
-use crate::storage::{Storage, TemplateStorageEntry};
+use crate::storage::Storage;
+use crate::template_cache;
 
-pub async fn process_connection(mut tls_stream: TlsStream<TcpStream>, storage: Arc<Storage>, settings: Arc<Settings>){
-    let template_storage = storage.template_storage.clone();
-    let request_storage = storage.request_queue.clone();
+/// Connection to a main server, failing reads of messages over max_message_bytes
+type Connection = LimitedReader<TlsStream<TcpStream>>;
+
+#[tracing::instrument(name = "connection", skip_all, fields(peer = tracing::field::Empty, client = tracing::field::Empty, request_id = tracing::field::Empty))]
+pub async fn process_connection(tls_stream: TlsStream<TcpStream>, storage: Arc<Storage>, settings: Arc<Settings>){
     let status_storage = storage.request_status.clone();
+    storage.metrics.connection("inbound");
+    if let Ok(peer) = tls_stream.get_ref().0.peer_addr(){
+        Span::current().record("peer", tracing::field::display(peer));
+    }
 
-    // Get rendering request
-    let mut rendering_request = match vb_exchange::read_message(&mut tls_stream).await{
-        Ok(msg) => {
-            if let Message::RenderingRequest(req) = msg{
-                req
-            }else{
-                eprintln!("Received unexpected Message type, closing connection.");
-                let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::UnexpectedMessageType)).await;
+    // Look up the policy of the connected main server
+    let client = match authorize_peer(&tls_stream, &settings){
+        Ok(client) => Arc::new(client),
+        Err(_) => return
+    };
+    Span::current().record("client", client.client_name.as_str());
+
+    let mut tls_stream = LimitedReader::new(tls_stream, settings.max_message_bytes);
+    let mut msg = match limits::read_message(&mut tls_stream).await{
+        Ok(msg) => msg,
+        Err(e) => {
+            warn!("{} Closed connection.", e);
+            return;
+        }
+    };
+
+    // Negotiate protocol version, main servers without a Hello speak version 1
+    let protocol = if let Message::Hello(hello) = &msg{
+        let protocol = match protocol::negotiate(hello){
+            Ok(protocol) => protocol,
+            Err(e) => {
+                warn!("{} Closing connection.", e);
+                let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::IncompatibleProtocolVersion(e))).await;
                 return;
             }
-        },
-        Err(_) => {
-            eprintln!("Error occured, closed connection.");
+        };
+        if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::HelloResponse(protocol.to_hello())).await{
+            warn!("Couldn't send hello to server. Closing connection");
             return;
         }
+        msg = match limits::read_message(&mut tls_stream).await{
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("{} Closed connection.", e);
+                return;
+            }
+        };
+        protocol
+    }else{
+        NegotiatedProtocol::legacy()
     };
 
-    let request_id = rendering_request.request_id.clone();
-
-    status_storage.write().unwrap().insert(rendering_request.request_id.clone(), RenderingStatus::SendToRenderingServer);
+    // Draining waits for this connection until it's closed
+    let _connection = storage.open_connection();
 
-    // Check if we have the template already stored (in the right version)
-    let template_stored = match template_storage.read().unwrap().get(&rendering_request.template_id){
-        Some(res) => {
-            res.version_id == rendering_request.template_version_id
+    // Get rendering request
+    let (mut rendering_request, admission) = match msg{
+        Message::RenderingRequest(req) => {
+            match admit_request(&storage, &settings, &client, &req){
+                Ok(admission) => (req, admission),
+                Err(reason) => {
+                    if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(protocol.downgrade_status(RenderingStatus::Rejected(reason)))).await{
+                        warn!("Couldn't send result to server. Closing connection");
+                    }
+                    return;
+                }
+            }
         },
-        None => false
-    };
-
-    if !template_stored{
-        // Update status
-        if let Some(status) = status_storage.write().unwrap().get_mut(&rendering_request.request_id){
-            *status = RenderingStatus::RequestingTemplate
-        }
-
-        // Request template from main server
-        if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::TemplateDataRequest(TemplateDataRequest{ template_id: rendering_request.template_id, template_version_id: rendering_request.template_version_id })).await{
-            eprintln!("Error occured requesting template data. Closing connection");
+        Message::TemplateDataResult(template_data) if protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => {
+            // Template pushed ahead of the first rendering request
+            if !client.permits(&settings, Action::UploadTemplate){
+                let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::Unauthorized)).await;
+                return;
+            }
+            let namespace = &client.template_namespace;
+            let (template_id, template_version_id) = (template_data.template_id, template_data.template_version_id);
+            let res = match save_template_data(&mut tls_stream, &storage, &settings, namespace, template_id, template_version_id, template_data).await{
+                Ok(export_formats) => register_template(&storage, &settings, namespace, template_id, template_version_id, export_formats).await,
+                Err(e) => Err(e)
+            };
+            if let Err(e) = res{
+                warn!("Couldn't save pushed template: {:?} Closing connection.", e);
+                return;
+            }
+            send_cached_templates(&mut tls_stream, &storage, namespace).await;
             return;
-        }
-        let template_data = match vb_exchange::read_message(&mut tls_stream).await {
-            Ok(msg) => {
-                if let Message::TemplateDataResult(msg) = msg {
-                    msg
-                } else {
-                    eprintln!("Received unexpected Message type, closing connection.");
-                    let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::UnexpectedMessageType)).await;
+        },
+        Message::TemplatePrefetchRequest(req) if protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => {
+            if !client.permits(&settings, Action::UploadTemplate){
+                let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::Unauthorized)).await;
+                return;
+            }
+            let namespace = &client.template_namespace;
+            if !template_cache::is_current_version(&storage, namespace, req.template_id, req.template_version_id){
+                if let Err(e) = request_template(&mut tls_stream, &storage, &settings, &protocol, namespace, req.template_id, req.template_version_id).await{
+                    warn!("Couldn't prefetch template: {:?} Closing connection.", e);
                     return;
                 }
             }
-            Err(_) => {
-                eprintln!("Error occured, closed connection.");
+            send_cached_templates(&mut tls_stream, &storage, namespace).await;
+            return;
+        },
+        Message::CachedTemplatesRequest if protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => {
+            send_cached_templates(&mut tls_stream, &storage, &client.template_namespace).await;
+            return;
+        },
+        Message::OpenSession if protocol.supports(protocol::FEATURE_SESSIONS) => {
+            session::run_session(tls_stream.into_inner(), storage, settings, protocol, client).await;
+            return;
+        },
+        Message::Drain if protocol.supports(protocol::FEATURE_DRAIN) => {
+            if !client.permits(&settings, Action::Drain){
+                let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::Unauthorized)).await;
                 return;
             }
-        };
-        if template_data.template_id != rendering_request.template_id || template_data.template_version_id != rendering_request.template_version_id{
-            eprintln!("Received unexpected template data, closing connection.");
-            let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::WrongTemplateDataSend)).await;
+            storage.request_drain();
+            if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::Capabilities(collect_capabilities(&storage, &settings))).await{
+                warn!("Couldn't send capabilities to server.");
+            }
             return;
-        }
-
-        if let Err(e) = template_data.contents.to_file(PathBuf::from(&settings.temp_template_path).join(template_data.template_version_id.to_string())).await{
-            eprintln!("Couldn't save template data to file: {}", e);
+        },
+        Message::CapabilitiesRequest if protocol.supports(protocol::FEATURE_CAPABILITIES) => {
+            if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::Capabilities(collect_capabilities(&storage, &settings))).await{
+                warn!("Couldn't send capabilities to server.");
+            }
+            return;
+        },
+        Message::RetainedWorkspaceRequest(req) if protocol.supports(protocol::FEATURE_WORKSPACE_RETENTION) => {
+            let archive = retained_workspace_archive(settings.clone(), req.request_id, &client).await;
+            if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RetainedWorkspace(RetainedWorkspace{ request_id: req.request_id, archive })).await{
+                warn!("Couldn't send retained workspace to server.");
+            }
+            return;
+        },
+        _ => {
+            warn!("Received unexpected Message type, closing connection.");
+            let _ = vb_exchange::send_message(&mut tls_stream, Message::CommunicationError(CommunicationError::UnexpectedMessageType)).await;
             return;
         }
+    };
 
-        let entry = TemplateStorageEntry{
-            version_id: rendering_request.template_version_id,
-            export_formats: template_data.export_formats,
-        };
-        template_storage.write().unwrap().insert(rendering_request.template_id, entry);
-    }
+    let request_id = rendering_request.request_id.clone();
+    Span::current().record("request_id", tracing::field::display(request_id));
+    info!(template_id = %rendering_request.template_id, export_formats = ?rendering_request.export_formats, "Received rendering request.");
 
-    if let FilesOnMemoryOrHarddrive::Memory(mem) = rendering_request.project_uploaded_files{
-        let id = uuid::Uuid::new_v4();
-        let path = PathBuf::from(format!("temp/{}", id));
+    status_storage.write().unwrap().insert(rendering_request.request_id.clone(), RenderingStatus::SendToRenderingServer);
 
-        if let Err(e) = tokio::fs::create_dir(&path).await{
-            eprintln!("Couldn't create new directory at {}: {}", path.to_str().unwrap_or(""), e);
-            return;
+    // Check if we have the template already stored (in the right version)
+    let cached = template_cache::is_current_version(&storage, &client.template_namespace, rendering_request.template_id, rendering_request.template_version_id);
+    storage.metrics.template_cache(cached);
+    if !cached{
+        // Update status
+        if let Some(status) = status_storage.write().unwrap().get_mut(&rendering_request.request_id){
+            *status = RenderingStatus::RequestingTemplate
         }
-        if let Err(e) = vb_exchange::recursive_write_dir_async(path.clone(), mem).await{
-            eprintln!("Couldn't put uploads to filesystem: {}", e);
-            status_storage.write().unwrap().insert(rendering_request.request_id.clone(), RenderingStatus::Failed(RenderingError::Other("IO Error saving uploads".to_string())));
+
+        // Request template from main server
+        if let Err(e) = request_template(&mut tls_stream, &storage, &settings, &protocol, &client.template_namespace, rendering_request.template_id, rendering_request.template_version_id).await{
+            warn!("Couldn't get template: {:?}", e);
+            fail_request(&mut tls_stream, &storage, &protocol, request_id, e).await;
             return;
         }
-        rendering_request.project_uploaded_files = FilesOnMemoryOrHarddrive::Harddrive(path);
     }
 
-    request_storage.write().unwrap().push_front(rendering_request);
+    if let Err(e) = save_uploads(&storage, &settings, &client, &mut rendering_request).await{
+        error!("Couldn't save uploads: {:?}", e);
+        fail_request(&mut tls_stream, &storage, &protocol, request_id, e).await;
+        return;
+    }
+
+    storage.enqueue(rendering_request, client.clone(), admission);
 
     // Fetch status of our rendering_request and send status updates
     loop{
@@ -111,24 +204,23 @@ pub async fn process_connection(mut tls_stream: TlsStream<TcpStream>, storage: A
                 RenderingStatus::Failed(RenderingError::Other("Not Found".to_string()))
             }
         };
-        //println!("Debug: Status {:?}", status);
         match status{
             // break if finished or failed
             RenderingStatus::Finished(_) => {
-                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(status)).await{
-                    eprintln!("Couldn't send result to server. Closing connection");
+                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(protocol.downgrade_status(status))).await{
+                    warn!("Couldn't send result to server. Closing connection");
                 }
                 break;
             }
-            RenderingStatus::Failed(_) => {
-                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(status)).await{
-                    eprintln!("Couldn't send result to server. Closing connection");
+            RenderingStatus::Failed(_) | RenderingStatus::Rejected(_) => {
+                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(protocol.downgrade_status(status))).await{
+                    warn!("Couldn't send result to server. Closing connection");
                 }
                 break;
             },
             _ => {
-                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(status)).await{
-                    eprintln!("Couldn't send status update to server. Closing connection");
+                if let Err(_) = vb_exchange::send_message(&mut tls_stream, Message::RenderingRequestStatus(protocol.downgrade_status(status))).await{
+                    warn!("Couldn't send status update to server. Closing connection");
                     break;
                 }
             }
@@ -138,4 +230,260 @@ pub async fn process_connection(mut tls_stream: TlsStream<TcpStream>, storage: A
 
     // Remove status
     let _ = status_storage.write().unwrap().remove(&request_id);
-}
\ No newline at end of file
+}
+
+/// Sends the error of a request failed before it was queued to the main server & removes its status
+async fn fail_request(tls_stream: &mut Connection, storage: &Storage, protocol: &NegotiatedProtocol, request_id: uuid::Uuid, error: RenderingError){
+    storage.request_status.write().unwrap().remove(&request_id);
+    if let Err(_) = vb_exchange::send_message(tls_stream, Message::RenderingRequestStatus(protocol.downgrade_status(RenderingStatus::Failed(error)))).await{
+        warn!("Couldn't send result to server. Closing connection");
+    }
+}
+
+/// Requests a template version from the main server and saves it to the template storage
+///
+/// If template_delta_transfer is enabled, the main server supports deltas and older versions of the template are cached,
+/// the main server may answer with a [Message::TemplateDeltaResult] against one of those versions instead of sending the whole template.
+async fn request_template(tls_stream: &mut Connection, storage: &Storage, settings: &Settings, protocol: &NegotiatedProtocol, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid) -> Result<(), RenderingError>{
+    let cached_versions = cached_versions_to_advertise(storage, settings, protocol, namespace, template_id);
+
+    let export_formats = if cached_versions.is_empty(){
+        request_full_template(tls_stream, storage, settings, namespace, template_id, template_version_id).await?
+    }else{
+        if let Err(_) = vb_exchange::send_message(tls_stream, Message::TemplateDeltaRequest(TemplateDeltaRequest{ template_id, template_version_id, cached_versions })).await{
+            return Err(RenderingError::Other("Error occured requesting template data.".to_string()));
+        }
+
+        match limits::read_message(tls_stream).await{
+            Ok(Message::TemplateDataResult(template_data)) => save_template_data(tls_stream, storage, settings, namespace, template_id, template_version_id, template_data).await?,
+            Ok(Message::TemplateDeltaResult(delta)) => {
+                if delta.template_id != template_id || delta.template_version_id != template_version_id{
+                    let _ = vb_exchange::send_message(tls_stream, Message::CommunicationError(CommunicationError::WrongTemplateDataSend)).await;
+                    return Err(RenderingError::Other("Received unexpected template delta.".to_string()));
+                }
+
+                match template_cache::assemble_from_delta(settings, namespace, delta).await{
+                    Ok(export_formats) => export_formats,
+                    Err(e) => {
+                        warn!("Couldn't assemble template from delta, requesting full template: {}", e);
+                        request_full_template(tls_stream, storage, settings, namespace, template_id, template_version_id).await?
+                    }
+                }
+            },
+            Ok(_) => {
+                let _ = vb_exchange::send_message(tls_stream, Message::CommunicationError(CommunicationError::UnexpectedMessageType)).await;
+                return Err(RenderingError::Other("Received unexpected Message type.".to_string()));
+            },
+            Err(e) => return Err(RenderingError::Other(format!("Error occured reading template data: {}", e)))
+        }
+    };
+
+    register_template(storage, settings, namespace, template_id, template_version_id, export_formats).await
+}
+
+/// Returns the cached versions of a template to offer as base for a delta, empty if deltas shouldn't be used
+pub fn cached_versions_to_advertise(storage: &Storage, settings: &Settings, protocol: &NegotiatedProtocol, namespace: &str, template_id: uuid::Uuid) -> Vec<TemplateVersionManifest>{
+    match storage.template_storage.read().unwrap().get(&(namespace.to_string(), template_id)){
+        Some(entry) if settings.template_delta_transfer && protocol.supports(protocol::FEATURE_TEMPLATE_DELTA) => entry.cached_versions.iter().map(|version| version.manifest.clone()).collect(),
+        _ => vec![]
+    }
+}
+
+/// Hashes a saved template version and makes it the current version of the template
+pub async fn register_template(storage: &Storage, settings: &Settings, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid, export_formats: HashMap<String, ExportFormat>) -> Result<(), RenderingError>{
+    let template_dir = template_cache::template_dir(settings, namespace, template_version_id);
+    let manifest = match tokio::task::spawn_blocking(move || template_cache::compute_manifest(&template_dir)).await{
+        Ok(Ok(manifest)) => manifest,
+        Ok(Err(e)) => return Err(RenderingError::Other(format!("Couldn't hash template files: {}", e))),
+        Err(e) => return Err(RenderingError::Other(format!("Couldn't hash template files: {}", e)))
+    };
+
+    template_cache::store_version(storage, settings, namespace, template_id, template_version_id, export_formats, manifest);
+
+    Ok(())
+}
+
+/// Sends the list of all cached template versions of the namespace to the main server
+async fn send_cached_templates(tls_stream: &mut Connection, storage: &Storage, namespace: &str){
+    let templates = template_cache::list_cached_templates(storage, namespace);
+    if let Err(_) = vb_exchange::send_message(tls_stream, Message::CachedTemplatesResult(CachedTemplatesResult{ templates })).await{
+        warn!("Couldn't send cached templates to server.");
+    }
+}
+
+/// Requests the complete template version from the main server and saves it to the temp template dir
+async fn request_full_template(tls_stream: &mut Connection, storage: &Storage, settings: &Settings, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid) -> Result<HashMap<String, ExportFormat>, RenderingError>{
+    if let Err(_) = vb_exchange::send_message(tls_stream, Message::TemplateDataRequest(TemplateDataRequest{ template_id, template_version_id })).await{
+        return Err(RenderingError::Other("Error occured requesting template data.".to_string()));
+    }
+
+    match limits::read_message(tls_stream).await{
+        Ok(Message::TemplateDataResult(template_data)) => save_template_data(tls_stream, storage, settings, namespace, template_id, template_version_id, template_data).await,
+        Ok(_) => {
+            let _ = vb_exchange::send_message(tls_stream, Message::CommunicationError(CommunicationError::UnexpectedMessageType)).await;
+            Err(RenderingError::Other("Received unexpected Message type.".to_string()))
+        },
+        Err(e) => Err(RenderingError::Other(format!("Error occured reading template data: {}", e)))
+    }
+}
+
+/// Checks that the received template data matches the requested version and writes it to the temp template dir
+async fn save_template_data(tls_stream: &mut Connection, storage: &Storage, settings: &Settings, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid, template_data: TemplateDataResult) -> Result<HashMap<String, ExportFormat>, RenderingError>{
+    if template_data.template_id != template_id || template_data.template_version_id != template_version_id{
+        let _ = vb_exchange::send_message(tls_stream, Message::CommunicationError(CommunicationError::WrongTemplateDataSend)).await;
+        return Err(RenderingError::Other("Received unexpected template data.".to_string()));
+    }
+
+    write_template_data(storage, settings, namespace, template_data).await
+}
+
+/// Writes received template data to a staging dir & moves it into the temp template dir once it's complete
+///
+/// If the version is already in place, e.g. saved by another connection meanwhile, it's kept.
+pub async fn write_template_data(storage: &Storage, settings: &Settings, namespace: &str, template_data: TemplateDataResult) -> Result<HashMap<String, ExportFormat>, RenderingError>{
+    let template_dir = template_cache::template_dir(settings, namespace, template_data.template_version_id);
+    let staging_dir = template_cache::staging_dir(&template_dir);
+    if let Err(e) = tokio::fs::create_dir_all(PathBuf::from(&settings.temp_template_path).join(namespace)).await{
+        return Err(RenderingError::Other(format!("Couldn't create template namespace directory: {}", e)));
+    }
+    let limits = TreeLimits::templates(settings);
+    safe_path::check_files(&template_data.contents)?;
+    limits::check_paths(&template_data.contents, &limits)?;
+    if let Err(e) = template_data.contents.to_file(staging_dir.clone()).await{
+        let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+        return Err(RenderingError::Other(format!("Couldn't save template data to file: {}", e)));
+    }
+    if let Err(e) = safe_path::check_tree(&staging_dir){
+        let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+        return Err(e.into());
+    }
+    let staging_dir_cpy = staging_dir.clone();
+    match tokio::task::spawn_blocking(move || limits::check_tree(&staging_dir_cpy, &limits)).await{
+        Ok(Ok(size)) => storage.metrics.bytes_received("templates", size),
+        Ok(Err(e)) => {
+            let _ = tokio::fs::remove_dir_all(&staging_dir).await;
+            return Err(e.into());
+        },
+        Err(_) => {}
+    }
+    match tokio::task::spawn_blocking(move || template_cache::move_into_place(&staging_dir, &template_dir)).await{
+        Ok(Ok(())) => {},
+        Ok(Err(e)) => return Err(RenderingError::Other(format!("Couldn't move template data into place: {}", e))),
+        Err(e) => return Err(RenderingError::Other(format!("Couldn't move template data into place: {}", e)))
+    }
+
+    Ok(template_data.export_formats)
+}
+
+/// Checks policy, drain mode, backlog & quotas of the client for a new rendering request
+///
+/// The returned admission counts as concurrent job of the client & in the backlog until it's queued.
+pub fn admit_request(storage: &Storage, settings: &Settings, client: &Authorization, rendering_request: &RenderingRequest) -> Result<Admission, RejectionReason>{
+    let res = check_admission(storage, settings, client, rendering_request);
+    if res.is_err(){
+        storage.metrics.rendering_request("rejected", None);
+    }
+    res
+}
+
+fn check_admission(storage: &Storage, settings: &Settings, client: &Authorization, rendering_request: &RenderingRequest) -> Result<Admission, RejectionReason>{
+    if !client.permits(settings, Action::Render){
+        return Err(RejectionReason::Unauthorized);
+    }
+    if settings.reject_jobs_while_draining && storage.is_draining(){
+        return Err(RejectionReason::Draining);
+    }
+    let reservation = admission::check_backlog(storage, settings, rendering_request.deadline)?;
+
+    let slot = storage.quotas.admit(&client.client_name, &client.limits).map_err(|e| {
+        warn!(client = %client.client_name, request_id = %rendering_request.request_id, "Rejected rendering request: {}", e);
+        RejectionReason::QuotaExceeded(e)
+    })?;
+    Ok(Admission{ slot, reservation })
+}
+
+/// Counts uploaded project files kept in memory for the quota of the client & writes them to a new directory inside the upload dir
+///
+/// Uploads with paths leaving the upload directory, symlinks, paths on the rendering server, exceeding the upload limits or the quota of the client are rejected.
+pub async fn save_uploads(storage: &Storage, settings: &Settings, client: &Authorization, rendering_request: &mut RenderingRequest) -> Result<(), RenderingError>{
+    let limits = TreeLimits::uploads(settings);
+    safe_path::check_files(&rendering_request.project_uploaded_files)?;
+    limits::check_paths(&rendering_request.project_uploaded_files, &limits)?;
+
+    let upload_bytes = match &rendering_request.project_uploaded_files{
+        FilesOnMemoryOrHarddrive::Memory(mem) => mem.file_sizes().iter().map(|(_, size)| size).sum(),
+        FilesOnMemoryOrHarddrive::Harddrive(_) => 0
+    };
+    if let Err(e) = storage.quotas.record_upload(&client.client_name, &client.limits, upload_bytes){
+        warn!(client = %client.client_name, request_id = %rendering_request.request_id, "Rejected uploads: {}", e);
+        return Err(RenderingError::LimitExceeded(e));
+    }
+
+    let id = uuid::Uuid::new_v4();
+    let path = PathBuf::from(&settings.upload_path).join(id.to_string());
+
+    if let Err(e) = tokio::fs::create_dir(&path).await{
+        error!("Couldn't create new directory at {}: {}", path.to_str().unwrap_or(""), e);
+        return Err(RenderingError::Other("IO Error saving uploads".to_string()));
+    }
+
+    let uploads = std::mem::replace(&mut rendering_request.project_uploaded_files, FilesOnMemoryOrHarddrive::Harddrive(path.clone()));
+    if let FilesOnMemoryOrHarddrive::Memory(mem) = uploads{
+        if let Err(e) = vb_exchange::recursive_write_dir_async(path.clone(), mem).await{
+            let _ = tokio::fs::remove_dir_all(&path).await;
+            error!("Couldn't put uploads to filesystem: {}", e);
+            return Err(RenderingError::Other("IO Error saving uploads".to_string()));
+        }
+    }
+    if let Err(e) = safe_path::check_tree(&path){
+        let _ = tokio::fs::remove_dir_all(&path).await;
+        return Err(e.into());
+    }
+
+    let path_cpy = path.clone();
+    match tokio::task::spawn_blocking(move || limits::check_tree(&path_cpy, &limits)).await{
+        Ok(Ok(size)) => storage.metrics.bytes_received("uploads", size),
+        Ok(Err(e)) => {
+            let _ = tokio::fs::remove_dir_all(&path).await;
+            return Err(e.into());
+        },
+        Err(e) => error!("Couldn't determine size of uploads: {}", e)
+    }
+
+    Ok(())
+}
+
+/// Packs the retained workspaces of a failed rendering request of the client, None if there are none
+pub async fn retained_workspace_archive(settings: Arc<Settings>, request_id: uuid::Uuid, client: &Authorization) -> Option<Vec<u8>>{
+    let client_name = client.client_name.clone();
+    match tokio::task::spawn_blocking(move || retention::archive(&settings, request_id, &client_name)).await{
+        Ok(Ok(archive)) => archive,
+        Ok(Err(e)) => {
+            error!(request_id = %request_id, "Couldn't archive retained workspace: {}", e);
+            None
+        },
+        Err(e) => {
+            error!(request_id = %request_id, "Couldn't archive retained workspace: {}", e);
+            None
+        }
+    }
+}
+
+/// Looks up the policy of the main server from its certificate, denials are written to the audit log
+pub fn authorize_peer(tls_stream: &TlsStream<TcpStream>, settings: &Settings) -> Result<Authorization, String>{
+    let identity = match ClientIdentity::from_peer_certificates(tls_stream.get_ref().1.peer_certificates()){
+        Ok(identity) => identity,
+        Err(e) => {
+            audit_denial(settings, &ClientIdentity::default(), "connection", &e);
+            return Err(e);
+        }
+    };
+
+    match authorization::authorize(settings, identity.clone()){
+        Ok(client) => Ok(client),
+        Err(e) => {
+            audit_denial(settings, &identity, "connection", &e);
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use vb_exchange::export_formats::{ExportFormat, ExportStepData};
+use vb_exchange::projects::PreparedProject;
+use crate::cli::DevArgs;
+use crate::http;
+use crate::http::{Request, Response};
+use crate::offline::read_json;
+use crate::rendering::{populate_workspace, render_raw_export_step, render_vivliostyle_export_step};
+use crate::safe_path;
+use crate::settings::{self, Settings};
+use crate::workspace::Workspace;
+
+/// Polled by served pages, which reload once the build number changes
+const VERSION_PATH: &str = "/__dev/version";
+const PDF_PATH: &str = "/__dev/pdf";
+const AUTO_REFRESH_SCRIPT: &str = r#"<script>(function(){let v=null;setInterval(async()=>{try{const t=await (await fetch("/__dev/version")).text();if(v!==null&&t!==v){location.reload();}v=t;}catch(e){}},1000);})();</script>"#;
+
+/// Result of the latest re-render
+#[derive(Default)]
+struct DevState{
+    build: u64,
+    /// Kept until the next build replaces it
+    workspace: Option<Workspace>,
+    html_file: Option<String>,
+    pdf_file: Option<String>,
+    log: String,
+    error: Option<String>,
+}
+
+/// Watches a template & sample project, re-renders on every change and serves the result with auto-refresh
+pub async fn run(settings: Arc<Settings>, args: DevArgs){
+    let work_dir = std::env::temp_dir().join(format!("vb-dev-{}", uuid::Uuid::new_v4()));
+    if let Err(e) = fs::create_dir_all(&work_dir){
+        eprintln!("Couldn't create working directory {}: {}", work_dir.to_string_lossy(), e);
+        return;
+    }
+    let mut settings = (*settings).clone();
+    settings.job_work_path = work_dir.to_string_lossy().to_string();
+    if let Err(problems) = settings.validate_working_dirs(){
+        eprintln!("{}", settings::describe_problems(&problems));
+        let _ = fs::remove_dir_all(&work_dir);
+        return;
+    }
+    let settings = Arc::new(settings);
+    let args = Arc::new(args);
+    let state = Arc::new(RwLock::new(DevState::default()));
+
+    let listener = match TcpListener::bind((args.bind.as_str(), args.port)).await{
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Couldn't listen on {}:{}: {}", args.bind, args.port, e);
+            return;
+        }
+    };
+    println!("Serving export format {} on http://{}:{}/", args.format, args.bind, args.port);
+
+    let state_cpy = state.clone();
+    tokio::spawn(http::serve(listener, move |request| {
+        let state = state_cpy.clone();
+        async move{ handle_request(request, &state) }
+    }));
+
+    let mut last_change = None;
+    loop{
+        let watched = [Some(args.template.clone()), Some(args.project.clone()), args.uploads.clone()];
+        let change = tokio::task::spawn_blocking(move || watched.iter().flatten().filter_map(|path| latest_modification(path).ok()).max()).await.ok().flatten();
+
+        if change != last_change{
+            last_change = change;
+            println!("Change detected, rendering...");
+            let settings_cpy = settings.clone();
+            let args_cpy = args.clone();
+            let build = tokio::task::spawn_blocking(move || render_once(&settings_cpy, &args_cpy)).await;
+
+            let mut state = state.write().unwrap();
+            state.build += 1;
+            match build{
+                Ok(Ok(build)) => {
+                    println!("Rendered build {}.", state.build);
+                    state.workspace = Some(build.workspace);
+                    state.html_file = build.html_file;
+                    state.pdf_file = build.pdf_file;
+                    state.log = build.log;
+                    state.error = None;
+                },
+                Ok(Err(e)) => {
+                    eprintln!("Rendering failed: {}", e);
+                    state.error = Some(e);
+                },
+                Err(e) => {
+                    eprintln!("Rendering failed: {}", e);
+                    state.error = Some(e.to_string());
+                }
+            }
+        }
+
+        tokio::select!{
+            _ = tokio::time::sleep(Duration::from_millis(500)) => {},
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    state.write().unwrap().workspace = None;
+    let _ = fs::remove_dir_all(&work_dir);
+}
+
+struct DevBuild{
+    workspace: Workspace,
+    html_file: Option<String>,
+    pdf_file: Option<String>,
+    log: String,
+}
+
+/// Runs the Raw step of the export format and, if enabled, its Vivliostyle step in one workspace
+fn render_once(settings: &Settings, args: &DevArgs) -> Result<DevBuild, String>{
+    let export_formats_path = args.export_formats.clone().unwrap_or_else(|| args.template.join("export_formats.json"));
+    let mut export_formats: HashMap<String, ExportFormat> = read_json(&export_formats_path)?;
+    let export_format = export_formats.remove(&args.format).ok_or_else(|| format!("Export format {} isn't defined in {}.", args.format, export_formats_path.to_string_lossy()))?;
+    let prepared_project: PreparedProject = read_json(&args.project)?;
+
+    let workspace = Workspace::create(settings).map_err(|e| format!("Couldn't create workspace: {}", e))?;
+    populate_workspace(workspace.path(), &args.template, &args.format, args.uploads.as_deref()).map_err(|e| format!("Couldn't prepare workspace: {}", e))?;
+
+    let mut log = String::new();
+    let mut html_file = None;
+    let mut pdf_file = None;
+    for step in export_format.export_steps{
+        match step.data{
+            ExportStepData::Raw(raw) if html_file.is_none() => {
+                let output_file = raw.output_file.clone();
+                render_raw_export_step(raw, workspace.path(), &prepared_project, &mut log).map_err(|e| format!("{:?}", e))?;
+                html_file = Some(output_file);
+            },
+            ExportStepData::Vivliostyle(vivlio) if args.vivliostyle && html_file.is_some() => {
+                let output_file = vivlio.output_file.clone();
+                render_vivliostyle_export_step(vivlio, workspace.path(), settings, &mut log).map_err(|e| format!("{:?}", e))?;
+                pdf_file = Some(output_file);
+                break;
+            },
+            _ => {}
+        }
+    }
+
+    if html_file.is_none(){
+        return Err(format!("Export format {} has no Raw step.", args.format));
+    }
+
+    Ok(DevBuild{ workspace, html_file, pdf_file, log })
+}
+
+/// Newest modification time of a file or of anything inside a directory
+fn latest_modification(path: &Path) -> io::Result<SystemTime>{
+    let meta = fs::metadata(path)?;
+    let mut latest = meta.modified()?;
+    if meta.is_dir(){
+        for entry in fs::read_dir(path)?{
+            if let Ok(modified) = latest_modification(&entry?.path()){
+                latest = latest.max(modified);
+            }
+        }
+    }
+    Ok(latest)
+}
+
+/// Answers with the rendered HTML, the PDF, the build number or a file of the workspace
+fn handle_request(request: Request, state: &RwLock<DevState>) -> Response{
+    let path = request.path.as_str();
+    let state = state.read().unwrap();
+    match (path, &state.error, state.workspace.as_ref().map(|workspace| workspace.path().clone())){
+        (VERSION_PATH, _, _) => Response::new("200 OK", "text/plain", state.build.to_string()),
+        ("/", Some(error), _) => Response::new("200 OK", "text/html", error_page(error, &state.log)),
+        (_, _, None) => Response::new("503 Service Unavailable", "text/html", error_page("Not rendered yet.", "")),
+        ("/", None, Some(dir)) => {
+            let html = state.html_file.as_ref().and_then(|file| fs::read_to_string(dir.join(file)).ok()).unwrap_or_default();
+            Response::new("200 OK", "text/html", inject_auto_refresh(&html))
+        },
+        (PDF_PATH, _, Some(dir)) => match state.pdf_file.as_ref().and_then(|file| fs::read(dir.join(file)).ok()){
+            Some(pdf) => Response::new("200 OK", "application/pdf", pdf),
+            None => Response::new("404 Not Found", "text/plain", "No PDF rendered, start with --vivliostyle")
+        },
+        (path, _, Some(dir)) => match safe_path::confine(&dir, path.trim_start_matches('/')).ok().and_then(|file| fs::read(file).ok()){
+            Some(content) => Response::new("200 OK", content_type_of(path), content),
+            None => Response::not_found()
+        }
+    }
+}
+
+fn inject_auto_refresh(html: &str) -> String{
+    match html.rfind("</body>"){
+        Some(pos) => format!("{}{}{}", &html[..pos], AUTO_REFRESH_SCRIPT, &html[pos..]),
+        None => format!("{}{}", html, AUTO_REFRESH_SCRIPT)
+    }
+}
+
+fn error_page(error: &str, log: &str) -> String{
+    let escape = |s: &str| s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+    format!("<!DOCTYPE html><html><head><title>Rendering failed</title></head><body><h1>Rendering failed</h1><pre>{}</pre><pre>{}</pre>{}</body></html>", escape(error), escape(log), AUTO_REFRESH_SCRIPT)
+}
+
+fn content_type_of(path: &str) -> &'static str{
+    match PathBuf::from(path).extension().and_then(|ext| ext.to_str()){
+        Some("html") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg") | Some("jpeg") => "image/jpeg",
+        Some("woff") => "font/woff",
+        Some("woff2") => "font/woff2",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream"
+    }
+}
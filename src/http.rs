@@ -0,0 +1,114 @@
+use std::future::Future;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::warn;
+
+/// Max size of the request line & headers, bodies aren't read
+const MAX_HEAD_BYTES: usize = 8192;
+
+/// Minimal HTTP/1.1 request of the local dev, metrics & admin endpoints
+pub struct Request{
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    pub query: Option<String>,
+    /// Header names & values, names as sent by the client
+    pub headers: Vec<(String, String)>,
+}
+
+impl Request{
+    /// Value of the first header with this name, ignoring case
+    pub fn header(&self, name: &str) -> Option<&str>{
+        self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+    }
+
+    /// Value of a query parameter, without percent-decoding
+    pub fn query_param(&self, name: &str) -> Option<&str>{
+        self.query.as_deref()?.split('&').filter_map(|pair| pair.split_once('=')).find(|(key, _)| *key == name).map(|(_, value)| value)
+    }
+}
+
+pub struct Response{
+    pub status: &'static str,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response{
+    pub fn new(status: &'static str, content_type: &'static str, body: impl Into<Vec<u8>>) -> Response{
+        Response{ status, content_type, body: body.into() }
+    }
+
+    pub fn not_found() -> Response{
+        Response::new("404 Not Found", "text/plain", "Not found")
+    }
+}
+
+/// Accepts connections forever, answering one request per connection with the handler
+pub async fn serve<H, F>(listener: TcpListener, handler: H)
+    where H: Fn(Request) -> F + Clone + Send + 'static, F: Future<Output = Response> + Send{
+    loop{
+        match listener.accept().await{
+            Ok((stream, _)) => {
+                let handler = handler.clone();
+                tokio::spawn(async move{
+                    if let Err(e) = answer(stream, handler).await{
+                        warn!("Couldn't answer HTTP request: {}", e);
+                    }
+                });
+            },
+            Err(e) => warn!("Couldn't accept HTTP connection: {}", e)
+        }
+    }
+}
+
+async fn answer<H, F>(mut stream: TcpStream, handler: H) -> io::Result<()>
+    where H: Fn(Request) -> F, F: Future<Output = Response>{
+    let request = match read_request(&mut stream).await?{
+        Some(request) => request,
+        None => return Ok(())
+    };
+    let response = handler(request).await;
+    respond(&mut stream, response).await
+}
+
+/// Reads the request line & headers, None if the client closed the connection or the head is too large
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Request>>{
+    let mut buf = vec![0u8; MAX_HEAD_BYTES];
+    let mut read = 0;
+    while !buf[..read].windows(4).any(|window| window == b"\r\n\r\n"){
+        if read == buf.len(){
+            respond(stream, Response::new("431 Request Header Fields Too Large", "text/plain", "Request too large")).await?;
+            return Ok(None);
+        }
+        let n = stream.read(&mut buf[read..]).await?;
+        if n == 0{
+            return Ok(None);
+        }
+        read += n;
+    }
+
+    let head = String::from_utf8_lossy(&buf[..read]);
+    let mut lines = head.lines();
+    let mut request_line = lines.next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or("GET").to_string();
+    let target = request_line.next().unwrap_or("/");
+    let (path, query) = match target.split_once('?'){
+        Some((path, query)) => (path.to_string(), Some(query.to_string())),
+        None => (target.to_string(), None)
+    };
+    let headers = lines.take_while(|line| !line.is_empty())
+        .filter_map(|line| line.split_once(':'))
+        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+        .collect();
+
+    Ok(Some(Request{ method, path, query, headers }))
+}
+
+async fn respond(stream: &mut TcpStream, response: Response) -> io::Result<()>{
+    let head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n", response.status, response.content_type, response.body.len());
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.shutdown().await
+}
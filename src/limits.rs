@@ -0,0 +1,296 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use vb_exchange::{FilesOnMemoryOrHarddrive, Message, RenderingError};
+use crate::settings::Settings;
+
+/// Upload or template exceeding one of the configured limits
+#[derive(Debug)]
+pub struct LimitExceeded(pub String);
+
+impl fmt::Display for LimitExceeded{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<LimitExceeded> for RenderingError{
+    fn from(e: LimitExceeded) -> Self{
+        RenderingError::LimitExceeded(e.0)
+    }
+}
+
+/// Limits of a file tree sent by a main server
+#[derive(Debug, Clone)]
+pub struct TreeLimits{
+    pub max_total_bytes: u64,
+    pub max_files: u64,
+    pub max_depth: u64,
+    pub max_file_bytes: u64,
+}
+
+impl TreeLimits{
+    pub fn uploads(settings: &Settings) -> TreeLimits{
+        TreeLimits{
+            max_total_bytes: settings.max_upload_bytes,
+            max_files: settings.max_files_per_tree,
+            max_depth: settings.max_tree_depth,
+            max_file_bytes: settings.max_file_bytes,
+        }
+    }
+
+    pub fn templates(settings: &Settings) -> TreeLimits{
+        TreeLimits{
+            max_total_bytes: settings.max_template_bytes,
+            max_files: settings.max_files_per_tree,
+            max_depth: settings.max_tree_depth,
+            max_file_bytes: settings.max_file_bytes,
+        }
+    }
+}
+
+/// Checks number, nesting & sizes of the files before they are written to disk
+pub fn check_paths(files: &FilesOnMemoryOrHarddrive, limits: &TreeLimits) -> Result<(), LimitExceeded>{
+    match files{
+        FilesOnMemoryOrHarddrive::Memory(mem) => check_listing(&mem.paths(), mem.file_sizes(), limits),
+        FilesOnMemoryOrHarddrive::Harddrive(_) => Ok(())
+    }
+}
+
+/// Checks the paths & sizes of an in-memory file tree
+fn check_listing(paths: &[String], file_sizes: impl IntoIterator<Item = (String, u64)>, limits: &TreeLimits) -> Result<(), LimitExceeded>{
+    if paths.len() as u64 > limits.max_files{
+        return Err(LimitExceeded(format!("{} files exceed the limit of {}.", paths.len(), limits.max_files)));
+    }
+    for path in paths{
+        if Path::new(path).components().count() as u64 > limits.max_depth{
+            return Err(LimitExceeded(format!("{} is nested deeper than {} directories.", path, limits.max_depth)));
+        }
+    }
+
+    let mut total: u64 = 0;
+    for (path, size) in file_sizes{
+        if size > limits.max_file_bytes{
+            return Err(LimitExceeded(format!("{} exceeds the file size limit of {} bytes.", path, limits.max_file_bytes)));
+        }
+        total = total.saturating_add(size);
+        if total > limits.max_total_bytes{
+            return Err(LimitExceeded(format!("Files exceed the limit of {} bytes.", limits.max_total_bytes)));
+        }
+    }
+    Ok(())
+}
+
+/// Checks a written file tree against all limits, returning its total size
+pub fn check_tree(dir: &Path, limits: &TreeLimits) -> Result<u64, LimitExceeded>{
+    let mut usage = TreeUsage::default();
+    walk_tree(dir, limits, 1, &mut usage).map_err(|e| LimitExceeded(format!("Couldn't check {}: {}", dir.to_string_lossy(), e)))??;
+    Ok(usage.bytes)
+}
+
+#[derive(Default)]
+struct TreeUsage{
+    bytes: u64,
+    files: u64,
+}
+
+fn walk_tree(dir: &Path, limits: &TreeLimits, depth: u64, usage: &mut TreeUsage) -> io::Result<Result<(), LimitExceeded>>{
+    if depth > limits.max_depth{
+        return Ok(Err(LimitExceeded(format!("{} is nested deeper than {} directories.", dir.to_string_lossy(), limits.max_depth))));
+    }
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let meta = entry.metadata()?;
+
+        usage.files += 1;
+        if usage.files > limits.max_files{
+            return Ok(Err(LimitExceeded(format!("More than {} files.", limits.max_files))));
+        }
+
+        if meta.is_dir(){
+            if let Err(e) = walk_tree(&entry.path(), limits, depth + 1, usage)?{
+                return Ok(Err(e));
+            }
+        }else{
+            if meta.len() > limits.max_file_bytes{
+                return Ok(Err(LimitExceeded(format!("{} exceeds the file size limit of {} bytes.", entry.path().to_string_lossy(), limits.max_file_bytes))));
+            }
+            usage.bytes += meta.len();
+            if usage.bytes > limits.max_total_bytes{
+                return Ok(Err(LimitExceeded(format!("Files exceed the limit of {} bytes.", limits.max_total_bytes))));
+            }
+        }
+    }
+    Ok(Ok(()))
+}
+
+/// Stream failing reads once the current message exceeds max_message_bytes, so oversized messages are never fully buffered
+pub struct LimitedReader<S>{
+    inner: S,
+    limit: u64,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<S> LimitedReader<S>{
+    pub fn new(inner: S, limit: u64) -> LimitedReader<S>{
+        LimitedReader{ inner, limit, remaining: limit, exceeded: false }
+    }
+
+    pub fn get_ref(&self) -> &S{
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S{
+        self.inner
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for LimitedReader<S>{
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>>{
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = res{
+            let read = (buf.filled().len() - before) as u64;
+            if read > self.remaining{
+                self.exceeded = true;
+                return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidData, "Message exceeds size limit")));
+            }
+            self.remaining -= read;
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for LimitedReader<S>{
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>>{
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>>{
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Reads the next message, failing as soon as it exceeds the limit of the reader
+pub async fn read_message<S: AsyncRead + Unpin>(reader: &mut LimitedReader<S>) -> Result<Message, String>{
+    reader.remaining = reader.limit;
+    match vb_exchange::read_message(&mut *reader).await{
+        Ok(msg) => Ok(msg),
+        Err(_) if reader.exceeded => Err(format!("Message exceeds the limit of {} bytes.", reader.limit)),
+        Err(_) => Err("Error occured reading message.".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::path::PathBuf;
+    use super::*;
+
+    fn limits() -> TreeLimits{
+        TreeLimits{ max_total_bytes: 100, max_files: 3, max_depth: 2, max_file_bytes: 60 }
+    }
+
+    fn temp_tree(files: &[(&str, usize)]) -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("vb-limits-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        for (path, size) in files{
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, vec![b'x'; *size]).unwrap();
+        }
+        dir
+    }
+
+    fn check(files: &[(&str, usize)]) -> Result<u64, LimitExceeded>{
+        let dir = temp_tree(files);
+        let res = check_tree(&dir, &limits());
+        fs::remove_dir_all(&dir).unwrap();
+        res
+    }
+
+    #[test]
+    fn check_tree_returns_total_size(){
+        assert_eq!(check(&[]).unwrap(), 0);
+        assert_eq!(check(&[("index.html", 60), ("style.css", 40)]).unwrap(), 100);
+    }
+
+    #[test]
+    fn check_tree_counts_directories_as_files(){
+        assert!(check(&[("a", 1), ("b", 1), ("c", 1)]).is_ok());
+        assert!(check(&[("a", 1), ("b", 1), ("c", 1), ("d", 1)]).is_err());
+        // assets/ and its file
+        assert!(check(&[("a", 1), ("assets/style.css", 1)]).is_ok());
+        assert!(check(&[("a", 1), ("b", 1), ("assets/style.css", 1)]).is_err());
+    }
+
+    #[test]
+    fn check_tree_rejects_deep_nesting(){
+        assert!(check(&[("a/b", 1)]).is_ok());
+        assert!(check(&[("a/b/c", 1)]).is_err());
+    }
+
+    #[test]
+    fn check_tree_rejects_large_files_and_totals(){
+        assert!(check(&[("big", 61)]).is_err());
+        assert!(check(&[("a", 50), ("b", 50), ("c", 1)]).is_err());
+    }
+
+    fn listing(files: &[(&str, u64)]) -> (Vec<String>, Vec<(String, u64)>){
+        (files.iter().map(|(path, _)| path.to_string()).collect(), files.iter().map(|(path, size)| (path.to_string(), *size)).collect())
+    }
+
+    fn check_memory(files: &[(&str, u64)]) -> Result<(), LimitExceeded>{
+        let (paths, sizes) = listing(files);
+        check_listing(&paths, sizes, &limits())
+    }
+
+    #[test]
+    fn check_listing_accepts_trees_within_limits(){
+        assert!(check_memory(&[]).is_ok());
+        assert!(check_memory(&[("index.html", 60), ("assets/style.css", 40)]).is_ok());
+    }
+
+    #[test]
+    fn check_listing_rejects_too_many_files(){
+        assert!(check_memory(&[("a", 1), ("b", 1), ("c", 1)]).is_ok());
+        assert!(check_memory(&[("a", 1), ("b", 1), ("c", 1), ("d", 1)]).is_err());
+    }
+
+    #[test]
+    fn check_listing_rejects_deep_nesting(){
+        assert!(check_memory(&[("a/b", 1)]).is_ok());
+        assert!(check_memory(&[("a/b/c", 1)]).is_err());
+    }
+
+    #[test]
+    fn check_listing_rejects_large_files(){
+        assert!(check_memory(&[("big", 61)]).is_err());
+    }
+
+    #[test]
+    fn check_listing_rejects_large_totals(){
+        assert!(check_memory(&[("a", 50), ("b", 50)]).is_ok());
+        assert!(check_memory(&[("a", 50), ("b", 50), ("c", 1)]).is_err());
+    }
+
+    #[test]
+    fn check_listing_total_doesnt_overflow(){
+        let limits = TreeLimits{ max_file_bytes: u64::MAX, ..limits() };
+        let (paths, sizes) = listing(&[("a", u64::MAX), ("b", u64::MAX)]);
+        assert!(check_listing(&paths, sizes, &limits).is_err());
+    }
+
+    #[test]
+    fn files_on_harddrive_are_checked_after_writing(){
+        assert!(check_paths(&FilesOnMemoryOrHarddrive::Harddrive("/tmp".into()), &limits()).is_ok());
+    }
+}
@@ -0,0 +1,396 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use serde::Serialize;
+use vb_exchange::export_formats::{ExportFormat, ExportStepData};
+use crate::cli::{LintArgs, LintOutput};
+use crate::offline::read_json;
+use crate::rendering::CUSTOM_HELPERS;
+use crate::safe_path;
+
+/// Helpers built into handlebars
+const BUILTIN_HELPERS: &[&str] = &["if", "unless", "each", "with", "lookup", "raw", "log", "eq", "ne", "gt", "gte", "lt", "lte", "and", "or", "not", "len"];
+const TEMPLATE_EXTENSION: &str = ".hbs.html";
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity{
+    Error,
+    Warning,
+}
+
+/// Problem found in a template, printed as JSON or as `file:line:column: severity[code]: message`
+#[derive(Debug, Serialize)]
+pub struct Diagnostic{
+    pub severity: Severity,
+    pub code: &'static str,
+    /// Path relative to the template directory
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl Diagnostic{
+    fn new(severity: Severity, code: &'static str, file: impl Into<String>, message: impl Into<String>) -> Diagnostic{
+        Diagnostic{ severity, code, file: file.into(), line: None, column: None, message: message.into() }
+    }
+
+    fn at(mut self, line: usize, column: usize) -> Diagnostic{
+        self.line = Some(line);
+        self.column = Some(column);
+        self
+    }
+
+    pub fn to_line(&self) -> String{
+        let severity = match self.severity{
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match (self.line, self.column){
+            (Some(line), Some(column)) => format!("{}:{}:{}: {}[{}]: {}", self.file, line, column, severity, self.code, self.message),
+            _ => format!("{}: {}[{}]: {}", self.file, severity, self.code, self.message),
+        }
+    }
+}
+
+/// Checks a template directory against the layout & rules the rendering pipeline relies on
+pub fn lint_template(template_dir: &Path, export_formats: &HashMap<String, ExportFormat>) -> Vec<Diagnostic>{
+    let mut diagnostics = Vec::new();
+
+    if !template_dir.join("assets").is_dir(){
+        diagnostics.push(Diagnostic::new(Severity::Error, "missing-assets", "assets", "Template has no assets/ directory."));
+    }
+
+    // Formats on disk without a definition are never rendered
+    if let Ok(entries) = fs::read_dir(template_dir.join("formats")){
+        for entry in entries.flatten(){
+            let slug = entry.file_name().to_string_lossy().to_string();
+            if !export_formats.contains_key(&slug){
+                diagnostics.push(Diagnostic::new(Severity::Warning, "unused-format-dir", format!("formats/{}", slug), format!("No export format {} is defined for this directory.", slug)));
+            }
+        }
+    }
+
+    let mut slugs: Vec<&String> = export_formats.keys().collect();
+    slugs.sort();
+    for slug in slugs{
+        lint_export_format(template_dir, slug, &export_formats[slug], &mut diagnostics);
+    }
+
+    diagnostics.sort_by(|a, b| (&a.file, a.line, a.column).cmp(&(&b.file, b.line, b.column)));
+    diagnostics
+}
+
+/// Lints the template given on the command line & prints the diagnostics
+///
+/// Returns whether the template passed, i.e. there were no errors (and no warnings with `--deny-warnings`).
+pub fn run(args: &LintArgs) -> Result<bool, String>{
+    let export_formats_path = args.export_formats.clone().unwrap_or_else(|| args.template.join("export_formats.json"));
+    let export_formats: HashMap<String, ExportFormat> = read_json(&export_formats_path)?;
+
+    let diagnostics = lint_template(&args.template, &export_formats);
+    match args.output{
+        LintOutput::Text => {
+            for diagnostic in &diagnostics{
+                println!("{}", diagnostic.to_line());
+            }
+        },
+        LintOutput::Json => {
+            println!("{}", serde_json::to_string_pretty(&diagnostics).map_err(|e| format!("Couldn't serialize diagnostics: {}", e))?);
+        }
+    }
+
+    Ok(!diagnostics.iter().any(|diagnostic| diagnostic.severity == Severity::Error || args.deny_warnings))
+}
+
+fn lint_export_format(template_dir: &Path, slug: &str, export_format: &ExportFormat, diagnostics: &mut Vec<Diagnostic>){
+    let format_dir = template_dir.join("formats").join(slug);
+    if let Err(e) = safe_path::relative(slug){
+        diagnostics.push(Diagnostic::new(Severity::Error, "unsafe-path", format!("formats/{}", slug), e.to_string()));
+        return;
+    }
+    if !format_dir.is_dir(){
+        diagnostics.push(Diagnostic::new(Severity::Error, "missing-format-dir", format!("formats/{}", slug), format!("Export format {} has no formats/{} directory.", slug, slug)));
+        return;
+    }
+
+    let files = match workspace_files(template_dir, slug){
+        Ok(files) => files,
+        Err(e) => {
+            diagnostics.push(Diagnostic::new(Severity::Error, "io", format!("formats/{}", slug), format!("Couldn't read template files: {}", e)));
+            return;
+        }
+    };
+
+    // Templates are registered by their path in the workspace without extension
+    let template_names: HashSet<String> = files.keys().filter_map(|path| path.strip_suffix(TEMPLATE_EXTENSION)).map(|name| name.to_string()).collect();
+    for (path, source_path) in &files{
+        if path.ends_with(TEMPLATE_EXTENSION){
+            let file = source_path.strip_prefix(template_dir).unwrap_or(source_path).to_string_lossy().to_string();
+            lint_handlebars_file(source_path, &file, &template_names, diagnostics);
+        }
+    }
+
+    let mut available: HashSet<String> = files.keys().cloned().collect();
+    let format_file = format!("formats/{}", slug);
+    for (index, step) in export_format.export_steps.iter().enumerate(){
+        let step_name = format!("step {} ({})", index + 1, step.name);
+        let (inputs, output) = match &step.data{
+            ExportStepData::Raw(raw) => {
+                if !template_names.contains(&raw.entry_point.replace(TEMPLATE_EXTENSION, "")){
+                    diagnostics.push(Diagnostic::new(Severity::Error, "missing-entry-point", &format_file, format!("Entry point {} of {} doesn't exist.", raw.entry_point, step_name)));
+                }
+                (vec![], raw.output_file.clone())
+            },
+            ExportStepData::Vivliostyle(vivlio) => (vec![vivlio.input_file.clone()], vivlio.output_file.clone()),
+            ExportStepData::Pandoc(pan) => {
+                let mut inputs = vec![pan.input_file.clone()];
+                inputs.extend(pan.metadata_file.clone());
+                inputs.extend(pan.epub_cover_image_path.clone());
+                inputs.extend(pan.epub_metadata_file.clone());
+                (inputs, pan.output_file.clone())
+            }
+        };
+
+        for path in inputs.iter().chain([&output]).chain(step.files_to_keep.iter()){
+            if let Err(e) = safe_path::relative(path){
+                diagnostics.push(Diagnostic::new(Severity::Error, "unsafe-path", &format_file, format!("{} in {}", e, step_name)));
+            }
+        }
+        for input in &inputs{
+            if !available.contains(input) && !input.starts_with("uploads/"){
+                diagnostics.push(Diagnostic::new(Severity::Warning, "missing-input", &format_file, format!("Input {} of {} isn't part of the template or created by an earlier step.", input, step_name)));
+            }
+        }
+
+        available.insert(output);
+        let mut kept = HashSet::new();
+        for file in &step.files_to_keep{
+            if available.contains(file){
+                // Kept files are copied by name into the workspace of the next step
+                kept.insert(Path::new(file).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default());
+            }else{
+                diagnostics.push(Diagnostic::new(Severity::Error, "unreachable-file-to-keep", &format_file, format!("File to keep {} of {} is neither part of the template nor created by this or an earlier step.", file, step_name)));
+            }
+        }
+        // The next step starts in a new workspace
+        available = files.keys().cloned().collect();
+        available.extend(kept);
+    }
+}
+
+/// Lists the files of the workspace of an export format, as laid out by the rendering pipeline, with their source paths
+///
+/// Global assets end up in `global_assets/`, files of `formats/<slug>` in the root and the contents of its subdirectories flattened into the root.
+fn workspace_files(template_dir: &Path, slug: &str) -> io::Result<HashMap<String, PathBuf>>{
+    let mut files = HashMap::new();
+
+    let assets_dir = template_dir.join("assets");
+    if assets_dir.is_dir(){
+        collect_files(&assets_dir, "global_assets/", &mut files)?;
+    }
+
+    for entry in fs::read_dir(template_dir.join("formats").join(slug))?{
+        let entry = entry?;
+        if entry.file_type()?.is_dir(){
+            collect_files(&entry.path(), "", &mut files)?;
+        }else{
+            files.insert(entry.file_name().to_string_lossy().to_string(), entry.path());
+        }
+    }
+
+    Ok(files)
+}
+
+fn collect_files(dir: &Path, prefix: &str, files: &mut HashMap<String, PathBuf>) -> io::Result<()>{
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir(){
+            collect_files(&entry.path(), &format!("{}/", name), files)?;
+        }else{
+            files.insert(name, entry.path());
+        }
+    }
+    Ok(())
+}
+
+/// Compiles a handlebars file and checks its helpers & partials
+fn lint_handlebars_file(path: &Path, file: &str, template_names: &HashSet<String>, diagnostics: &mut Vec<Diagnostic>){
+    let source = match fs::read_to_string(path){
+        Ok(source) => source,
+        Err(e) => {
+            diagnostics.push(Diagnostic::new(Severity::Error, "io", file, format!("Couldn't read template: {}", e)));
+            return;
+        }
+    };
+
+    if let Err(e) = handlebars::Template::compile(&source){
+        let diagnostic = Diagnostic::new(Severity::Error, "syntax", file, e.reason().to_string());
+        diagnostics.push(match e.pos(){
+            Some((line, column)) => diagnostic.at(line, column),
+            None => diagnostic
+        });
+        return;
+    }
+
+    let mustaches = mustaches(&source);
+    let inline_partials: HashSet<String> = mustaches.iter().filter_map(|m| m.content.strip_prefix("#*inline")).filter_map(first_token).collect();
+
+    for mustache in &mustaches{
+        let content = mustache.content;
+        if content.starts_with('!') || content.starts_with('/') || content.starts_with("#*") || content.starts_with("else"){
+            continue;
+        }
+
+        if let Some(rest) = content.strip_prefix("#>").or_else(|| content.strip_prefix('>')){
+            if let Some(partial) = first_token(rest){
+                if !template_names.contains(&partial) && !inline_partials.contains(&partial){
+                    diagnostics.push(Diagnostic::new(Severity::Error, "missing-partial", file, format!("Partial {} doesn't exist.", partial)).at(mustache.line, mustache.column));
+                }
+            }
+            continue;
+        }
+
+        let (block, expression) = match content.strip_prefix('#').or_else(|| content.strip_prefix('^')){
+            Some(rest) => (true, rest),
+            None => (false, content)
+        };
+        let mut helpers = subexpression_helpers(expression);
+        // Expressions without parameters are variable lookups unless they open a block
+        if block || expression.split_whitespace().count() > 1{
+            helpers.extend(first_token(expression));
+        }
+
+        for helper in helpers{
+            if !BUILTIN_HELPERS.contains(&helper.as_str()) && !CUSTOM_HELPERS.contains(&helper.as_str()){
+                diagnostics.push(Diagnostic::new(Severity::Error, "unknown-helper", file, format!("Helper {} doesn't exist.", helper)).at(mustache.line, mustache.column));
+            }
+        }
+    }
+}
+
+/// Content of a `{{ }}` expression with its position
+struct Mustache<'a>{
+    content: &'a str,
+    line: usize,
+    column: usize,
+}
+
+/// Finds all handlebars expressions, with braces, whitespace control & escapes stripped
+fn mustaches(source: &str) -> Vec<Mustache<'_>>{
+    let mut res = Vec::new();
+    let mut pos = 0;
+
+    while let Some(start) = source[pos..].find("{{").map(|start| start + pos){
+        let comment = source[start..].starts_with("{{!--");
+        let end_marker = if comment{ "--}}" }else{ "}}" };
+        let end = match source[start + 2..].find(end_marker){
+            Some(end) => start + 2 + end,
+            None => break,
+        };
+
+        let content = source[start..end].trim_start_matches('{').trim_start_matches('~').trim_end_matches('~').trim();
+        let before = &source[..start];
+        let line = before.matches('\n').count() + 1;
+        let column = start - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+        res.push(Mustache{ content, line, column });
+
+        pos = end + end_marker.len();
+    }
+
+    res
+}
+
+/// First whitespace separated token without quotes
+fn first_token(s: &str) -> Option<String>{
+    let token = s.split_whitespace().next()?.trim_matches(|c| c == '"' || c == '\'' || c == ')');
+    // Dynamic partials & subexpressions can't be checked
+    if token.is_empty() || token.starts_with('(') || token.contains('=') || token.starts_with('@') || token.starts_with("../") || token == "this"{
+        return None;
+    }
+    Some(token.to_string())
+}
+
+/// Names of helpers called in subexpressions like `(helper arg)`, skipping quoted strings
+fn subexpression_helpers(expression: &str) -> Vec<String>{
+    let mut helpers = Vec::new();
+    let mut quote = None;
+
+    for (i, c) in expression.char_indices(){
+        match (quote, c){
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {},
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '(') => helpers.extend(first_token(&expression[i + 1..])),
+            _ => {}
+        }
+    }
+
+    helpers
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn contents(source: &str) -> Vec<&str>{
+        mustaches(source).iter().map(|m| m.content).collect()
+    }
+
+    #[test]
+    fn finds_expressions_with_positions(){
+        let found = mustaches("<h1>{{title}}</h1>\n  {{#if subtitle}}");
+        assert_eq!(found.len(), 2);
+        assert_eq!((found[0].content, found[0].line, found[0].column), ("title", 1, 5));
+        assert_eq!((found[1].content, found[1].line, found[1].column), ("#if subtitle", 2, 3));
+    }
+
+    #[test]
+    fn strips_braces_and_whitespace_control(){
+        assert_eq!(contents("{{{raw_html}}} {{~#each items~}} {{ spaced }}"), vec!["raw_html", "#each items", "spaced"]);
+    }
+
+    #[test]
+    fn block_comments_may_contain_braces(){
+        assert_eq!(contents("{{!-- {{not_a_helper x}} --}}{{after}}"), vec!["!-- {{not_a_helper x}}", "after"]);
+    }
+
+    #[test]
+    fn stops_at_unclosed_expressions(){
+        assert_eq!(contents("{{ok}} {{broken"), vec!["ok"]);
+    }
+
+    #[test]
+    fn first_token_skips_dynamic_names(){
+        assert_eq!(first_token(" \"footer\" title=x"), Some("footer".to_string()));
+        assert_eq!(first_token("header"), Some("header".to_string()));
+        assert_eq!(first_token("(lookup . 'name')"), None);
+        assert_eq!(first_token("this"), None);
+        assert_eq!(first_token("@index"), None);
+        assert_eq!(first_token("   "), None);
+    }
+
+    #[test]
+    fn finds_helpers_of_subexpressions_outside_quotes(){
+        assert_eq!(subexpression_helpers("lookup (concat a (upper b)) \"(not_a_helper\""), vec!["concat".to_string(), "upper".to_string()]);
+        assert!(subexpression_helpers("title").is_empty());
+    }
+
+    #[test]
+    fn reports_unknown_helpers_and_partials(){
+        let dir = std::env::temp_dir().join(format!("vb-lint-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("index.hbs.html");
+        fs::write(&path, "{{#if a}}{{shout b}}{{/if}}{{title}}{{> header}}{{> missing}}\n{{#*inline \"footer\"}}x{{/inline}}{{> footer}}").unwrap();
+
+        let mut diagnostics = Vec::new();
+        lint_handlebars_file(&path, "index.hbs.html", &HashSet::from(["header".to_string()]), &mut diagnostics);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let codes: Vec<(&str, Option<usize>)> = diagnostics.iter().map(|d| (d.code, d.column)).collect();
+        assert_eq!(codes, vec![("unknown-helper", Some(10)), ("missing-partial", Some(49))]);
+    }
+}
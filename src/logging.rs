@@ -0,0 +1,29 @@
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+use crate::settings::Settings;
+
+/// Output format of log messages
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat{
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including the fields of all enclosing spans
+    Json,
+}
+
+/// Installs the global logger, filtered by RUST_LOG or else log_level
+pub fn init(settings: &Settings) -> Result<(), String>{
+    let filter = match EnvFilter::try_from_default_env(){
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&settings.log_level).map_err(|e| format!("Invalid log_level {}: {}", settings.log_level, e))?
+    };
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let res = match settings.log_format{
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).with_span_list(true).try_init()
+    };
+
+    res.map_err(|e| format!("Couldn't initialize logging: {}", e))
+}
@@ -2,99 +2,322 @@
 //!
 //! It listens to incoming TCP requests from a main server.
 //! Only connections with a valid certificate signed by the CA are accepted (mTLS).
+//! The certificate's common name or DNS SANs select the client policy (see [authorization]), which decides what the
+//! main server may do and in which template namespace its templates are cached.
 //!
 //! # Communication Protocol
 //! Main Server -> Rendering Server, establish TCP Connection
 //!
+//! Main Server -> Rendering Server (optional): supported protocol versions & features: [vb_exchange::Message::Hello]
+//!
+//! Rendering Server -> Main Server: negotiated protocol version & features: [vb_exchange::Message::HelloResponse],
+//! or [vb_exchange::CommunicationError::IncompatibleProtocolVersion] if the supported versions don't overlap.
+//! Main servers skipping the Hello are treated as protocol version 1 without optional features. They get rejections,
+//! limit & path errors as [vb_exchange::RenderingError::Other]. Messages of features not negotiated are answered with
+//! [vb_exchange::CommunicationError::UnexpectedMessageType].
+//!
 //! Main Server -> Rendering Server: [vb_exchange::Message::RenderingRequest]
 //!
 //! Rendering Server -> Main Server: If template data not saved in current version: [vb_exchange::Message::TemplateDataRequest]
 //!
 //! Main Server -> Rendering Server: Send Template data (if requested): [vb_exchange::Message::TemplateDataResult]
 //!
+//! If template_delta_transfer is enabled, the template_delta feature was negotiated and older versions of the template are cached, the rendering server sends a
+//! [vb_exchange::Message::TemplateDeltaRequest] listing those versions with their file hashes instead. The main server
+//! answers with a [vb_exchange::Message::TemplateDeltaResult] containing only the changed files against one of them,
+//! or with the full [vb_exchange::Message::TemplateDataResult].
+//!
 //! Rendering Server -> Main Server: Send Rendering Status update: [vb_exchange::Message::RenderingRequestStatus]
 //!
 //! Rendering Server -> Main Server: Send Rendering Result: [vb_exchange::Message::RenderingResult]
+//!
+//! ## Admission
+//! Rendering requests are answered with [vb_exchange::RenderingStatus::Rejected] right away if the main server's policy
+//! doesn't allow rendering, its quotas are used up, or the rendering server is busy. It's busy if max_queue_depth requests
+//! are queued or the estimated completion time exceeds the deadline of the request. Busy rejections contain the seconds
+//! after which the main server may retry ([vb_exchange::RejectionReason::Busy]), it can fail over to another rendering
+//! server in the meantime.
+//!
+//! Messages over max_message_bytes close the connection while they are read. Uploads & templates exceeding the size,
+//! file count or nesting limits fail with [vb_exchange::RenderingError::LimitExceeded] before rendering.
+//!
+//! ## Template pre-warming
+//! Instead of a rendering request, the main server may open a connection with one of these messages. The rendering
+//! server answers each of them with a [vb_exchange::Message::CachedTemplatesResult] and closes the connection.
+//!
+//! * [vb_exchange::Message::TemplateDataResult]: push a template version
+//! * [vb_exchange::Message::TemplatePrefetchRequest]: let the rendering server request a template version, as described above
+//! * [vb_exchange::Message::CachedTemplatesRequest]: only list the cached template versions
+//!
+//! ## Sessions
+//! If the sessions feature was negotiated, the main server may send [vb_exchange::Message::OpenSession] instead of a
+//! rendering request and keep the connection open. It can then send any number of [vb_exchange::Message::RenderingRequest]s,
+//! template & capability messages. Status updates & results are sent as [vb_exchange::Message::TaggedRenderingStatus]
+//! whenever the status of a request changes. Template requests work as above, their answers are matched by template version.
+//! [vb_exchange::Message::CloseSession] ends the session.
+//!
+//! ## Reverse connections
+//! If main_server_address is set, the rendering server additionally connects to the main server itself, e.g. if it is
+//! behind NAT. It sends its [vb_exchange::Message::Hello], expects a [vb_exchange::Message::HelloResponse] with the
+//! sessions feature and registers with its [vb_exchange::Message::Capabilities]. The connection is then used as a
+//! session opened by the main server. Broken connections are reestablished with exponential backoff.
+//!
+//! ## Drain
+//! On SIGTERM/SIGINT or a [vb_exchange::Message::Drain] (answered with [vb_exchange::Message::Capabilities]) the server
+//! stops accepting connections, rejects new requests on open connections with [vb_exchange::RejectionReason::Draining]
+//! (if reject_jobs_while_draining is set), waits up to shutdown_deadline seconds for queued & running requests, cleans
+//! the job directories and exits.
+//!
+//! ## Failed workspaces
+//! If retain_failed_workspaces is set or the rendering request asks for it, the working directories of failed export
+//! formats are kept for failed_workspace_retention seconds.
+//!
+//! Main Server -> Rendering Server: [vb_exchange::Message::RetainedWorkspaceRequest]
+//!
+//! Rendering Server -> Main Server: tar.gz archive of the workspaces, if any were kept for this main server: [vb_exchange::Message::RetainedWorkspace]
+//!
+//! ## Capabilities
+//! Main Server -> Rendering Server: [vb_exchange::Message::CapabilitiesRequest]
+//!
+//! Rendering Server -> Main Server: supported step types, engine versions, limits & current load: [vb_exchange::Message::Capabilities]
 
-use std::fs::{create_dir, remove_dir_all};
-use std::path::Path;
 use std::sync::Arc;
+use clap::Parser;
 use tokio::net::TcpListener;
-use tokio_rustls::rustls::server::WebPkiClientVerifier;
-use tokio_rustls::rustls::ServerConfig;
 use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, warn};
+use crate::cli::{Cli, Command, ServeArgs};
 use crate::settings::Settings;
-use vb_exchange::certs::*;
 use crate::connection_handler::process_connection;
 use crate::rendering::rendering_worker;
+use crate::reverse_connect::run_reverse_connection;
+use crate::self_check::SelfCheckMode;
 use crate::storage::Storage;
+use crate::tls::TlsConfigs;
 
 pub mod settings;
 pub mod storage;
 pub mod connection_handler;
 pub mod rendering;
+pub mod template_cache;
+pub mod self_check;
+pub mod capabilities;
+pub mod protocol;
+pub mod session;
+pub mod reverse_connect;
+pub mod shutdown;
+pub mod tls;
+pub mod authorization;
+pub mod quotas;
+pub mod admission;
+pub mod safe_path;
+pub mod limits;
+pub mod workspace;
+pub mod retention;
+pub mod cli;
+pub mod offline;
+pub mod lint;
+pub mod dev;
+pub mod http;
+pub mod commands;
+pub mod logging;
+pub mod metrics;
+pub mod admin;
 
 #[tokio::main]
 async fn main() {
-    let settings : Arc<Settings> = Arc::new(Settings::new().expect("Couldn't read config(s)!"));
-
-    // Clear template folder or create if it doesn't exist
-    let path = Path::new(&settings.temp_template_path);
-    if !path.exists(){
-        if let Err(e) = tokio::fs::create_dir(path).await{
-            eprintln!("Couldn't create new temp template dir: {}. Check your temp_template_path setting & file permissions.", e);
-            return;
+    let cli = Cli::parse();
+
+    if let Some(Command::LintTemplate(args)) = &cli.command{
+        match lint::run(args){
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    let mut settings = match Settings::new(cli.config.as_deref()){
+        Ok(settings) => settings,
+        Err(e) => {
+            if cli.version{
+                commands::print_version(None);
+                return;
+            }
+            eprintln!("Couldn't read config(s): {}", e);
+            std::process::exit(1);
         }
-    }else {
-        if let Err(e) = storage::clear_template_dir(&settings) {
-            eprintln!("Couldn't clear template dir: {}", e);
-            return;
+    };
+
+    if cli.version{
+        commands::print_version(Some(&settings));
+        return;
+    }
+
+    if let Err(e) = logging::init(&settings){
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    match cli.command.unwrap_or(Command::Serve(ServeArgs::default())){
+        Command::Serve(args) => {
+            args.apply(&mut settings);
+            serve(Arc::new(settings)).await;
+        },
+        Command::CheckEnv => {
+            if !commands::check_env(&settings){
+                std::process::exit(1);
+            }
+        },
+        Command::Cache{ command } => {
+            if let Err(e) = commands::cache(&settings, &command){
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        },
+        Command::Render(args) => {
+            match offline::render(&settings, &args){
+                Ok(true) => {},
+                Ok(false) => std::process::exit(1),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+        },
+        Command::Dev(args) => dev::run(Arc::new(settings), args).await,
+        // Handled before reading the config
+        Command::LintTemplate(_) => {}
+    }
+}
+
+/// Listens for main servers until a shutdown signal arrives
+async fn serve(settings: Arc<Settings>){
+    if let Err(problems) = settings.validate(){
+        for problem in problems{
+            error!("Invalid config: {}", problem);
         }
+        std::process::exit(1);
     }
 
-    // Remove and re-crate temp dir
-    let temp_dir_path = Path::new("temp");
-    let _ = remove_dir_all(temp_dir_path);
-    create_dir(temp_dir_path).unwrap();
+    // Held until the server exits, so cache commands & other servers don't touch the working dirs meanwhile
+    let _working_dir_lock = match storage::lock_working_dirs(&settings){
+        Ok(lock) => lock,
+        Err(e) => {
+            error!("Couldn't lock working directories: {}.", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Clear template, job & upload folders or create them if they don't exist
+    if let Err(e) = storage::prepare_working_dirs(&settings){
+        error!("Couldn't prepare working directories: {}. Check your temp_template_path, job_work_path & upload_path settings & file permissions.", e);
+        std::process::exit(1);
+    }
 
     let storage = Arc::new(Storage::new());
 
+    // Check sandbox & rendering environments
+    info!("Checking rendering environments...");
+    let settings_cpy = settings.clone();
+    let environment = match tokio::task::spawn_blocking(move || self_check::check_environment(&settings_cpy)).await{
+        Ok(res) => res,
+        Err(e) => {
+            error!("Couldn't run self-check: {}", e);
+            std::process::exit(1);
+        }
+    };
+    info!(bwrap = environment.bwrap_version.as_deref().unwrap_or("missing"), vivliostyle = environment.vivliostyle.version.as_deref().unwrap_or("unavailable"), pandoc = environment.pandoc.version.as_deref().unwrap_or("unavailable"), "Checked rendering environments.");
+    let problems = environment.problems();
+    if !problems.is_empty(){
+        for problem in &problems{
+            error!("Self-check failed: {}", problem);
+        }
+        if settings.self_check_mode == SelfCheckMode::Strict{
+            error!("Refusing to start. Fix the problems above or set self_check_mode to \"degrade\".");
+            std::process::exit(1);
+        }
+    }
+    *storage.environment.write().unwrap() = environment;
+
     // Load certs
-    let root_ca = Arc::new(load_root_ca(settings.ca_cert_path.clone()));
-    let client_cert = load_client_cert(settings.client_cert_path.clone());
-    let client_key = load_private_key(settings.client_key_path.clone());
-    let crls = load_crl(settings.revocation_list_path.clone());
+    let tls_configs = match TlsConfigs::load(&settings){
+        Ok(res) => Arc::new(res),
+        Err(e) => {
+            error!("{}. Check Certs & Key!", e);
+            std::process::exit(1);
+        }
+    };
 
-    // Server Config
-    let client_verifier = WebPkiClientVerifier::builder(root_ca.clone()).with_crls(crls).build().expect("Couldn't build Client Verifier. Check Certs & Key!");
+    let tls_configs_cpy = tls_configs.clone();
+    let settings_cpy = settings.clone();
+    tokio::spawn(async move{
+        tls::reload_on_change(tls_configs_cpy, settings_cpy).await;
+    });
 
-    let server_config = ServerConfig::builder_with_protocol_versions(&[&tokio_rustls::rustls::version::TLS13])
-        .with_client_cert_verifier(client_verifier)
-        .with_single_cert(client_cert.clone(), client_key).expect("Couldn't build Server Config. Check Certs & Key!");
+    // Connect to main server if configured
+    if let Some(main_server_address) = settings.main_server_address.clone(){
+        let storage_cpy = storage.clone();
+        let settings_cpy = settings.clone();
+        let tls_configs_cpy = tls_configs.clone();
+        tokio::spawn(async move{
+            info!(main_server = %main_server_address, "Connecting to main server.");
+            run_reverse_connection(main_server_address, storage_cpy, settings_cpy, tls_configs_cpy).await;
+        });
+    }
 
     // Create Server to listen on incoming rendering requests
-    let acceptor = TlsAcceptor::from(Arc::new(server_config));
-    let listener = TcpListener::bind(format!("{}:{}", settings.bind_to_host, settings.port)).await.unwrap();
+    let listener = match settings.listen_address(){
+        Ok(address) => match TcpListener::bind(address).await{
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("Couldn't listen on {}: {}", address, e);
+                std::process::exit(1);
+            }
+        },
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     // Spawn rendering thread
     let storage_cpy = storage.clone();
     let settings_cpy = settings.clone();
     tokio::spawn(async move{
-        println!("Starting rendering worker.");
+        info!("Starting rendering worker.");
         rendering_worker(storage_cpy, settings_cpy).await;
     });
 
+    // Spawn janitor for job directories left behind
+    tokio::spawn(workspace::run_janitor(storage.clone(), settings.clone()));
+
+    if let Some(metrics_address) = settings.metrics_address{
+        tokio::spawn(metrics::serve(metrics_address, storage.clone()));
+    }
+    if let Some(admin_address) = settings.admin_address{
+        tokio::spawn(admin::serve(admin_address, storage.clone(), settings.clone()));
+    }
+
+    let shutdown_signal = shutdown::wait_for_shutdown(&storage);
+    tokio::pin!(shutdown_signal);
+
     loop{
-        let (socket, incoming_address) = match listener.accept().await{
-            Ok(res) => res,
-            Err(e) => {
-                eprintln!("Failed to establish connection: {}", e);
-                continue;
-            }
+        let (socket, incoming_address) = tokio::select!{
+            res = listener.accept() => match res{
+                Ok(res) => res,
+                Err(e) => {
+                    warn!("Failed to establish connection: {}", e);
+                    continue;
+                }
+            },
+            _ = &mut shutdown_signal => break
         };
 
-        println!("Got an connection from: {}", incoming_address);
-        let acceptor = acceptor.clone();
+        debug!(peer = %incoming_address, "Accepted connection.");
+        let acceptor = TlsAcceptor::from(tls_configs.server_config());
 
         let storage_cpy = storage.clone();
         let settings_cpy = settings.clone();
@@ -102,11 +325,22 @@ async fn main() {
             match acceptor.accept(socket).await{
                 Ok(tls_stream) => process_connection(tls_stream.into(), storage_cpy, settings_cpy).await,
                 Err(e) => {
-                    eprintln!("Failed to accept TLS connection: {}", e);
+                    warn!(peer = %incoming_address, "Failed to accept TLS connection: {}", e);
                 }
             }
         });
     }
+
+    // Stop accepting connections, finish running requests & clean up
+    drop(listener);
+    shutdown::drain(&storage, &settings).await;
+    if let Err(e) = storage::clear_job_dirs(&settings){
+        error!("Couldn't clean job directories: {}", e);
+    }
+    info!("Shut down.");
+
+    // Don't wait for blocking rendering threads, sandboxes left after the deadline are killed with this process
+    std::process::exit(0);
 }
 
 
@@ -0,0 +1,213 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+use crate::http;
+use crate::http::Response;
+use crate::storage::Storage;
+
+/// Upper bounds in seconds of the duration histograms
+const DURATION_BUCKETS: &[f64] = &[0.1, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0];
+
+/// Counters & histograms of the rendering server, exposed in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics{
+    /// Connections from or to main servers, by kind (inbound, reverse)
+    connections: Family<Counter>,
+    /// Rendering requests, by outcome (finished, failed, rejected)
+    rendering_requests: Family<Counter>,
+    /// Duration of rendering requests from start of rendering until all export formats finished
+    rendering_request_duration: Family<Histogram>,
+    /// Export formats, by outcome
+    export_formats: Family<Counter>,
+    export_format_duration: Family<Histogram>,
+    /// Export steps, by engine (raw, vivliostyle, pandoc) & outcome
+    export_steps: Family<Counter>,
+    /// Duration of export steps, by engine
+    export_step_duration: Family<Histogram>,
+    /// Template lookups of rendering requests, by result (hit, miss)
+    template_cache: Family<Counter>,
+    /// Bytes received from main servers, by kind (uploads, templates)
+    bytes_received: Family<Counter>,
+    /// Bytes of result files sent to main servers
+    bytes_sent: Counter,
+}
+
+impl Metrics{
+    pub fn connection(&self, kind: &str){
+        self.connections.get(&[("kind", kind)]).inc(1);
+    }
+
+    /// Counts a rendering request that ended with the given outcome, the duration is only known for rendered requests
+    pub fn rendering_request(&self, outcome: &str, duration: Option<Duration>){
+        self.rendering_requests.get(&[("outcome", outcome)]).inc(1);
+        if let Some(duration) = duration{
+            self.rendering_request_duration.get(&[]).observe(duration);
+        }
+    }
+
+    pub fn export_format(&self, success: bool, duration: Duration){
+        self.export_formats.get(&[("outcome", outcome(success))]).inc(1);
+        self.export_format_duration.get(&[]).observe(duration);
+    }
+
+    pub fn export_step(&self, engine: &str, success: bool, duration: Duration){
+        self.export_steps.get(&[("engine", engine), ("outcome", outcome(success))]).inc(1);
+        self.export_step_duration.get(&[("engine", engine)]).observe(duration);
+    }
+
+    pub fn template_cache(&self, hit: bool){
+        self.template_cache.get(&[("result", if hit{ "hit" }else{ "miss" })]).inc(1);
+    }
+
+    pub fn bytes_received(&self, kind: &str, bytes: u64){
+        self.bytes_received.get(&[("kind", kind)]).inc(bytes);
+    }
+
+    pub fn bytes_sent(&self, bytes: u64){
+        self.bytes_sent.inc(bytes);
+    }
+
+    /// Renders all metrics & the current load of the storage in the Prometheus text format
+    pub fn render(&self, storage: &Storage) -> String{
+        let mut out = String::new();
+
+        gauge(&mut out, "vb_queued_requests", "Rendering requests waiting for a free rendering thread", storage.request_queue.read().unwrap().len() as u64);
+        gauge(&mut out, "vb_running_requests", "Rendering requests currently rendering", storage.running_jobs.load(Ordering::Relaxed));
+        gauge(&mut out, "vb_draining", "1 while the server drains before shutting down", storage.is_draining() as u64);
+
+        self.connections.render_counters(&mut out, "vb_connections_total", "Connections from or to main servers");
+        self.rendering_requests.render_counters(&mut out, "vb_rendering_requests_total", "Rendering requests by outcome");
+        self.rendering_request_duration.render_histograms(&mut out, "vb_rendering_request_duration_seconds", "Duration of rendered requests");
+        self.export_formats.render_counters(&mut out, "vb_export_formats_total", "Rendered export formats by outcome");
+        self.export_format_duration.render_histograms(&mut out, "vb_export_format_duration_seconds", "Duration of export formats");
+        self.export_steps.render_counters(&mut out, "vb_export_steps_total", "Export steps by engine & outcome");
+        self.export_step_duration.render_histograms(&mut out, "vb_export_step_duration_seconds", "Duration of export steps by engine");
+        self.template_cache.render_counters(&mut out, "vb_template_cache_requests_total", "Template lookups of rendering requests by result");
+        self.bytes_received.render_counters(&mut out, "vb_received_bytes_total", "Bytes of uploads & templates received from main servers");
+
+        let _ = writeln!(out, "# HELP vb_sent_bytes_total Bytes of result files sent to main servers\n# TYPE vb_sent_bytes_total counter\nvb_sent_bytes_total {}", self.bytes_sent.get());
+
+        out
+    }
+}
+
+fn outcome(success: bool) -> &'static str{
+    if success{ "success" }else{ "failure" }
+}
+
+fn braced(labels: &str) -> String{
+    if labels.is_empty(){ String::new() }else{ format!("{{{}}}", labels) }
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64){
+    let _ = writeln!(out, "# HELP {} {}\n# TYPE {} gauge\n{} {}", name, help, name, name, value);
+}
+
+#[derive(Default)]
+struct Counter(AtomicU64);
+
+impl Counter{
+    fn inc(&self, by: u64){
+        self.0.fetch_add(by, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> u64{
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+struct Histogram{
+    /// Observations per bucket of DURATION_BUCKETS, not cumulative
+    buckets: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Default for Histogram{
+    fn default() -> Self{
+        Histogram{
+            buckets: DURATION_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Histogram{
+    fn observe(&self, duration: Duration){
+        let seconds = duration.as_secs_f64();
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound){
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.sum_micros.fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Metrics of one name, by label values
+#[derive(Default)]
+struct Family<T>{
+    /// Keyed by the rendered labels, e.g. `engine="pandoc",outcome="success"`
+    members: Mutex<BTreeMap<String, Arc<T>>>,
+}
+
+impl<T: Default> Family<T>{
+    fn get(&self, labels: &[(&str, &str)]) -> Arc<T>{
+        let key = labels.iter().map(|(name, value)| format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))).collect::<Vec<_>>().join(",");
+        self.members.lock().unwrap().entry(key).or_default().clone()
+    }
+}
+
+impl Family<Counter>{
+    fn render_counters(&self, out: &mut String, name: &str, help: &str){
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+        for (labels, counter) in self.members.lock().unwrap().iter(){
+            let _ = writeln!(out, "{}{} {}", name, braced(labels), counter.get());
+        }
+    }
+}
+
+impl Family<Histogram>{
+    fn render_histograms(&self, out: &mut String, name: &str, help: &str){
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} histogram", name, help, name);
+        for (labels, histogram) in self.members.lock().unwrap().iter(){
+            let separator = if labels.is_empty(){ "" }else{ "," };
+            let mut cumulative = 0;
+            for (bound, count) in DURATION_BUCKETS.iter().zip(&histogram.buckets){
+                cumulative += count.load(Ordering::Relaxed);
+                let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, separator, bound, cumulative);
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, separator, count);
+            let _ = writeln!(out, "{}_sum{} {}", name, braced(labels), histogram.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0);
+            let _ = writeln!(out, "{}_count{} {}", name, braced(labels), count);
+        }
+    }
+}
+
+/// Serves the metrics at `/metrics` on the given address
+pub async fn serve(address: SocketAddr, storage: Arc<Storage>){
+    let listener = match TcpListener::bind(address).await{
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Couldn't listen for metrics on {}: {}", address, e);
+            return;
+        }
+    };
+    info!("Serving metrics on http://{}/metrics.", address);
+
+    http::serve(listener, move |request| {
+        let storage = storage.clone();
+        async move{
+            match request.path.as_str(){
+                "/metrics" => Response::new("200 OK", "text/plain; version=0.0.4", storage.metrics.render(&storage)),
+                _ => Response::not_found()
+            }
+        }
+    }).await
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use vb_exchange::{FilesOnMemoryOrHarddrive, RenderingRequest};
+use vb_exchange::export_formats::ExportFormat;
+use vb_exchange::projects::PreparedProject;
+use crate::cli::RenderArgs;
+use crate::rendering::{copy_dir_all, render_export_format};
+use crate::self_check;
+use crate::settings::{self, Settings};
+use crate::storage::Storage;
+use crate::template_cache;
+
+/// Template namespace & client name of locally rendered templates
+const LOCAL: &str = "local";
+
+/// Renders export formats of a local template & project through the same pipeline as rendering requests
+///
+/// Rendered files & the rendering log of each export format are written to `<output>/<slug>`, errors to `<output>/<slug>/error.log`.
+/// Uses its own working directories inside the output dir, so it can run next to a server with the same config.
+/// Returns whether all export formats rendered successfully.
+pub fn render(settings: &Settings, args: &RenderArgs) -> Result<bool, String>{
+    let work_dir = args.output.join(".work");
+    let mut settings = settings.clone();
+    settings.temp_template_path = work_dir.join("templates").to_string_lossy().to_string();
+    settings.job_work_path = work_dir.join("jobs").to_string_lossy().to_string();
+    settings.retained_workspace_path = args.output.join("failed").to_string_lossy().to_string();
+    settings.validate_working_dirs().map_err(|problems| settings::describe_problems(&problems))?;
+    for dir in [&settings.temp_template_path, &settings.job_work_path]{
+        fs::create_dir_all(dir).map_err(|e| format!("Couldn't create working directory {}: {}", dir, e))?;
+    }
+
+    let res = render_in_work_dir(&settings, args, &work_dir);
+    let _ = fs::remove_dir_all(&work_dir);
+    res
+}
+
+fn render_in_work_dir(settings: &Settings, args: &RenderArgs, work_dir: &Path) -> Result<bool, String>{
+    let export_formats_path = args.export_formats.clone().unwrap_or_else(|| args.template.join("export_formats.json"));
+    let export_formats: HashMap<String, ExportFormat> = read_json(&export_formats_path)?;
+    let prepared_project: PreparedProject = read_json(&args.project)?;
+
+    let uploads = match &args.uploads{
+        Some(uploads) => uploads.clone(),
+        None => {
+            let uploads = work_dir.join("uploads");
+            fs::create_dir_all(&uploads).map_err(|e| format!("Couldn't create uploads directory: {}", e))?;
+            uploads
+        }
+    };
+
+    // Cache the template like one sent by a main server
+    let storage = Arc::new(Storage::new());
+    let template_id = uuid::Uuid::new_v4();
+    let template_version_id = uuid::Uuid::new_v4();
+    let template_dir = template_cache::template_dir(settings, LOCAL, template_version_id);
+    copy_dir_all(&args.template, &template_dir).map_err(|e| format!("Couldn't copy template: {}", e))?;
+    let manifest = template_cache::compute_manifest(&template_dir).map_err(|e| format!("Couldn't hash template files: {}", e))?;
+    template_cache::store_version(&storage, settings, LOCAL, template_id, template_version_id, export_formats, manifest);
+
+    println!("Checking rendering environments...");
+    let environment = self_check::check_environment(settings);
+    for problem in environment.problems(){
+        eprintln!("Self-check failed: {}", problem);
+    }
+    *storage.environment.write().unwrap() = environment;
+
+    let request = Arc::new(RenderingRequest{
+        request_id: uuid::Uuid::new_v4(),
+        template_id,
+        template_version_id,
+        export_formats: args.formats.clone(),
+        prepared_project,
+        project_uploaded_files: FilesOnMemoryOrHarddrive::Harddrive(uploads),
+        deadline: None,
+        retain_failed_workspace: args.keep_failed,
+    });
+
+    let mut succeeded = true;
+    for slug in &args.formats{
+        println!("Rendering export format {}...", slug);
+        let format_dir = args.output.join(slug);
+        let _ = fs::remove_dir_all(&format_dir);
+        fs::create_dir_all(&format_dir).map_err(|e| format!("Couldn't create output directory: {}", e))?;
+
+        match render_export_format(slug.clone(), storage.clone(), request.clone(), LOCAL, LOCAL, settings){
+            Ok(res) => {
+                for file in &res.files_to_transfer{
+                    let name = file.file_name().unwrap_or("invalid_filename".as_ref());
+                    fs::copy(file, format_dir.join(name)).map_err(|e| format!("Couldn't copy {}: {}", file.to_string_lossy(), e))?;
+                    println!("Wrote {}.", format_dir.join(name).to_string_lossy());
+                }
+                fs::write(format_dir.join("rendering.log"), &res.log).map_err(|e| format!("Couldn't write rendering log: {}", e))?;
+            },
+            Err(e) => {
+                succeeded = false;
+                eprintln!("Export format {} failed, see {}.", slug, format_dir.join("error.log").to_string_lossy());
+                fs::write(format_dir.join("error.log"), format!("{:?}", e)).map_err(|e| format!("Couldn't write error log: {}", e))?;
+            }
+        }
+    }
+
+    Ok(succeeded)
+}
+
+/// Reads & deserializes a JSON file
+pub fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, String>{
+    let content = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path.to_string_lossy(), e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Couldn't parse {}: {}", path.to_string_lossy(), e))
+}
@@ -0,0 +1,170 @@
+use vb_exchange::{Hello, RenderingError, RenderingStatus};
+
+/// Newest protocol version spoken by this server
+pub const PROTOCOL_VERSION: u32 = 2;
+/// Oldest protocol version still accepted. Version 1 are main servers without a [vb_exchange::Message::Hello]
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+/// First protocol version knowing [RenderingStatus::Rejected] and the limit, path & authorization errors
+const DETAILED_STATUS_VERSION: u32 = 2;
+
+/// Rendering server may request templates as delta against cached versions
+pub const FEATURE_TEMPLATE_DELTA: &str = "template_delta";
+/// Main server may push & prefetch templates and list cached templates
+pub const FEATURE_TEMPLATE_PREFETCH: &str = "template_prefetch";
+/// Main server may request the capabilities of the rendering server
+pub const FEATURE_CAPABILITIES: &str = "capabilities";
+
+/// Main server may open a multiplexed session for many rendering requests
+pub const FEATURE_SESSIONS: &str = "sessions";
+
+/// Main server may ask the rendering server to drain & shut down, e.g. for rolling upgrades
+pub const FEATURE_DRAIN: &str = "drain";
+
+/// Main server may download the retained workspaces of failed rendering requests
+pub const FEATURE_WORKSPACE_RETENTION: &str = "workspace_retention";
+
+/// All optional features supported by this server
+pub const FEATURES: &[&str] = &[FEATURE_TEMPLATE_DELTA, FEATURE_TEMPLATE_PREFETCH, FEATURE_CAPABILITIES, FEATURE_SESSIONS, FEATURE_DRAIN, FEATURE_WORKSPACE_RETENTION];
+
+/// Protocol version & features agreed on with the main server for one connection
+#[derive(Debug, Clone)]
+pub struct NegotiatedProtocol{
+    pub version: u32,
+    pub features: Vec<String>,
+}
+
+impl NegotiatedProtocol{
+    /// Protocol of main servers which don't send a [vb_exchange::Message::Hello]
+    pub fn legacy() -> Self{
+        NegotiatedProtocol{
+            version: 1,
+            features: vec![],
+        }
+    }
+
+    pub fn supports(&self, feature: &str) -> bool{
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// Converts a status into one the main server can decode
+    ///
+    /// Main servers speaking version 1 only know the generic errors, they get rejections, limit & path errors as [RenderingError::Other].
+    pub fn downgrade_status(&self, status: RenderingStatus) -> RenderingStatus{
+        if self.version >= DETAILED_STATUS_VERSION{
+            return status;
+        }
+        match status{
+            RenderingStatus::Rejected(reason) => RenderingStatus::Failed(RenderingError::Other(format!("Rejected: {:?}", reason))),
+            RenderingStatus::Failed(RenderingError::LimitExceeded(e)) => RenderingStatus::Failed(RenderingError::Other(format!("Limit exceeded: {}", e))),
+            RenderingStatus::Failed(RenderingError::PathViolation(e)) => RenderingStatus::Failed(RenderingError::Other(e)),
+            status => status
+        }
+    }
+
+    /// Hello to send back to the main server
+    pub fn to_hello(&self) -> Hello{
+        Hello{
+            protocol_version: self.version,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            features: self.features.clone(),
+            server_version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// Hello offering every version & feature of this server, sent when connecting to a main server
+pub fn offered_hello() -> Hello{
+    NegotiatedProtocol{
+        version: PROTOCOL_VERSION,
+        features: FEATURES.iter().map(|f| f.to_string()).collect(),
+    }.to_hello()
+}
+
+/// Picks the newest protocol version both sides support and the features both sides know
+///
+/// Returns a human-readable error if the supported version ranges don't overlap.
+pub fn negotiate(hello: &Hello) -> Result<NegotiatedProtocol, String>{
+    let version = hello.protocol_version.min(PROTOCOL_VERSION);
+
+    if version < MIN_PROTOCOL_VERSION || version < hello.min_protocol_version{
+        return Err(format!("Incompatible protocol versions: main server {} supports {}-{}, rendering server {} supports {}-{}. Upgrade the older side.",
+                           hello.server_version, hello.min_protocol_version, hello.protocol_version,
+                           env!("CARGO_PKG_VERSION"), MIN_PROTOCOL_VERSION, PROTOCOL_VERSION));
+    }
+
+    let features = hello.features.iter().filter(|f| FEATURES.contains(&f.as_str())).cloned().collect();
+
+    Ok(NegotiatedProtocol{ version, features })
+}
+
+#[cfg(test)]
+mod tests{
+    use vb_exchange::RejectionReason;
+    use super::*;
+
+    fn hello(protocol_version: u32, min_protocol_version: u32, features: &[&str]) -> Hello{
+        Hello{
+            protocol_version,
+            min_protocol_version,
+            features: features.iter().map(|f| f.to_string()).collect(),
+            server_version: "test".to_string(),
+        }
+    }
+
+    #[test]
+    fn main_servers_without_hello_speak_version_1(){
+        let protocol = NegotiatedProtocol::legacy();
+        assert_eq!(protocol.version, 1);
+        for feature in FEATURES{
+            assert!(!protocol.supports(feature));
+        }
+    }
+
+    #[test]
+    fn picks_newest_common_version(){
+        assert_eq!(negotiate(&hello(PROTOCOL_VERSION, 1, &[])).unwrap().version, PROTOCOL_VERSION);
+        assert_eq!(negotiate(&hello(PROTOCOL_VERSION + 3, 1, &[])).unwrap().version, PROTOCOL_VERSION);
+        assert_eq!(negotiate(&hello(1, 1, &[])).unwrap().version, 1);
+    }
+
+    #[test]
+    fn rejects_versions_without_overlap(){
+        // Main server too new
+        assert!(negotiate(&hello(PROTOCOL_VERSION + 3, PROTOCOL_VERSION + 1, &[])).is_err());
+        // Main server too old
+        assert!(negotiate(&hello(MIN_PROTOCOL_VERSION - 1, 0, &[])).is_err());
+    }
+
+    #[test]
+    fn keeps_only_features_both_sides_know(){
+        let protocol = negotiate(&hello(PROTOCOL_VERSION, 1, &[FEATURE_TEMPLATE_DELTA, "teleportation"])).unwrap();
+        assert_eq!(protocol.features, vec![FEATURE_TEMPLATE_DELTA.to_string()]);
+        assert!(protocol.supports(FEATURE_TEMPLATE_DELTA));
+        assert!(!protocol.supports(FEATURE_CAPABILITIES));
+    }
+
+    #[test]
+    fn hello_response_carries_negotiated_protocol(){
+        let protocol = negotiate(&hello(PROTOCOL_VERSION, 1, &[FEATURE_CAPABILITIES])).unwrap();
+        let response = protocol.to_hello();
+        assert_eq!(response.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(response.min_protocol_version, MIN_PROTOCOL_VERSION);
+        assert_eq!(response.features, vec![FEATURE_CAPABILITIES.to_string()]);
+    }
+
+    #[test]
+    fn downgrades_new_statuses_for_version_1(){
+        let legacy = NegotiatedProtocol::legacy();
+        assert!(matches!(legacy.downgrade_status(RenderingStatus::Rejected(RejectionReason::Draining)), RenderingStatus::Failed(RenderingError::Other(_))));
+        assert!(matches!(legacy.downgrade_status(RenderingStatus::Failed(RenderingError::LimitExceeded("too big".to_string()))), RenderingStatus::Failed(RenderingError::Other(_))));
+        assert!(matches!(legacy.downgrade_status(RenderingStatus::Failed(RenderingError::PathViolation("../x".to_string()))), RenderingStatus::Failed(RenderingError::Other(_))));
+        assert!(matches!(legacy.downgrade_status(RenderingStatus::Failed(RenderingError::TemplateNotFound)), RenderingStatus::Failed(RenderingError::TemplateNotFound)));
+    }
+
+    #[test]
+    fn keeps_new_statuses_for_version_2(){
+        let protocol = negotiate(&hello(2, 1, &[])).unwrap();
+        assert!(matches!(protocol.downgrade_status(RenderingStatus::Rejected(RejectionReason::Draining)), RenderingStatus::Rejected(RejectionReason::Draining)));
+        assert!(matches!(protocol.downgrade_status(RenderingStatus::Failed(RenderingError::LimitExceeded("too big".to_string()))), RenderingStatus::Failed(RenderingError::LimitExceeded(_))));
+    }
+}
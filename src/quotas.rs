@@ -0,0 +1,201 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Window for the hourly limits
+const WINDOW: Duration = Duration::from_secs(3600);
+
+/// Limits of one client, None means unlimited
+#[derive(Debug, Clone, Default)]
+pub struct QuotaLimits{
+    /// Queued & running rendering requests
+    pub max_concurrent_jobs: Option<u64>,
+    pub max_jobs_per_hour: Option<u64>,
+    /// Bytes of uploaded project files
+    pub max_upload_bytes_per_hour: Option<u64>,
+    /// Render time summed over all export formats, in seconds
+    pub max_render_seconds_per_hour: Option<u64>,
+}
+
+#[derive(Default)]
+struct ClientUsage{
+    active_jobs: u64,
+    jobs: VecDeque<Instant>,
+    upload_bytes: VecDeque<(Instant, u64)>,
+    render_time: VecDeque<(Instant, Duration)>,
+}
+
+impl ClientUsage{
+    /// Forgets usage older than the window
+    fn expire(&mut self, now: Instant){
+        while self.jobs.front().is_some_and(|time| now.duration_since(*time) > WINDOW){
+            self.jobs.pop_front();
+        }
+        while self.upload_bytes.front().is_some_and(|(time, _)| now.duration_since(*time) > WINDOW){
+            self.upload_bytes.pop_front();
+        }
+        while self.render_time.front().is_some_and(|(time, _)| now.duration_since(*time) > WINDOW){
+            self.render_time.pop_front();
+        }
+    }
+}
+
+/// Tracks the usage of every client by the name of its policy
+#[derive(Default)]
+pub struct QuotaTracker{
+    usage: Mutex<HashMap<String, ClientUsage>>,
+}
+
+impl QuotaTracker{
+    /// Checks the limits of the client and reserves a concurrent job for the new request
+    ///
+    /// Returns a human-readable reason if a limit is reached.
+    pub fn admit(self: &Arc<Self>, client_name: &str, limits: &QuotaLimits) -> Result<JobSlot, String>{
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(client_name.to_string()).or_default();
+        usage.expire(now);
+
+        if let Some(max) = limits.max_concurrent_jobs{
+            if usage.active_jobs >= max{
+                return Err(format!("Limit of {} concurrent rendering requests reached.", max));
+            }
+        }
+        if let Some(max) = limits.max_jobs_per_hour{
+            if usage.jobs.len() as u64 >= max{
+                return Err(format!("Limit of {} rendering requests per hour reached.", max));
+            }
+        }
+        if let Some(max) = limits.max_render_seconds_per_hour{
+            if usage.render_time.iter().map(|(_, time)| *time).sum::<Duration>() >= Duration::from_secs(max){
+                return Err(format!("Limit of {} seconds render time per hour reached.", max));
+            }
+        }
+
+        usage.active_jobs += 1;
+        usage.jobs.push_back(now);
+
+        Ok(JobSlot{
+            tracker: self.clone(),
+            client_name: client_name.to_string(),
+        })
+    }
+
+    /// Counts uploads of the given size for the client if they fit into its hourly limit
+    ///
+    /// Returns a human-readable reason if they don't.
+    pub fn record_upload(&self, client_name: &str, limits: &QuotaLimits, bytes: u64) -> Result<(), String>{
+        let now = Instant::now();
+        let mut usage = self.usage.lock().unwrap();
+        let usage = usage.entry(client_name.to_string()).or_default();
+        usage.expire(now);
+
+        if let Some(max) = limits.max_upload_bytes_per_hour{
+            let used = usage.upload_bytes.iter().map(|(_, bytes)| bytes).sum::<u64>();
+            if used.saturating_add(bytes) > max{
+                return Err(format!("Uploads of {} bytes exceed the limit of {} uploaded bytes per hour, {} bytes left.", bytes, max, max.saturating_sub(used)));
+            }
+        }
+
+        usage.upload_bytes.push_back((now, bytes));
+        Ok(())
+    }
+
+    pub fn record_render_time(&self, client_name: &str, time: Duration){
+        self.usage.lock().unwrap().entry(client_name.to_string()).or_default().render_time.push_back((Instant::now(), time));
+    }
+}
+
+/// Counts as a concurrent job of the client until dropped
+pub struct JobSlot{
+    tracker: Arc<QuotaTracker>,
+    client_name: String,
+}
+
+impl Drop for JobSlot{
+    fn drop(&mut self){
+        if let Some(usage) = self.tracker.usage.lock().unwrap().get_mut(&self.client_name){
+            usage.active_jobs = usage.active_jobs.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn tracker() -> Arc<QuotaTracker>{
+        Arc::new(QuotaTracker::default())
+    }
+
+    #[test]
+    fn unlimited_clients_are_always_admitted(){
+        let tracker = tracker();
+        let slots: Vec<JobSlot> = (0..100).map(|_| tracker.admit("client", &QuotaLimits::default()).unwrap()).collect();
+        assert_eq!(slots.len(), 100);
+    }
+
+    #[test]
+    fn concurrent_jobs_are_released_on_drop(){
+        let tracker = tracker();
+        let limits = QuotaLimits{ max_concurrent_jobs: Some(2), ..Default::default() };
+
+        let first = tracker.admit("client", &limits).unwrap();
+        let second = tracker.admit("client", &limits).unwrap();
+        assert!(tracker.admit("client", &limits).is_err());
+
+        drop(first);
+        let third = tracker.admit("client", &limits).unwrap();
+        assert!(tracker.admit("client", &limits).is_err());
+
+        drop(second);
+        drop(third);
+        assert!(tracker.admit("client", &limits).is_ok());
+    }
+
+    #[test]
+    fn jobs_per_hour_count_released_jobs(){
+        let tracker = tracker();
+        let limits = QuotaLimits{ max_jobs_per_hour: Some(2), ..Default::default() };
+
+        drop(tracker.admit("client", &limits).unwrap());
+        drop(tracker.admit("client", &limits).unwrap());
+        assert!(tracker.admit("client", &limits).is_err());
+    }
+
+    #[test]
+    fn uploads_are_limited_by_their_size(){
+        let tracker = tracker();
+        let limits = QuotaLimits{ max_upload_bytes_per_hour: Some(1000), ..Default::default() };
+
+        assert!(tracker.record_upload("client", &limits, 600).is_ok());
+        // Rejected as a whole, without counting
+        assert!(tracker.record_upload("client", &limits, 401).is_err());
+        assert!(tracker.record_upload("client", &limits, 400).is_ok());
+        assert!(tracker.record_upload("client", &limits, 1).is_err());
+        assert!(tracker.record_upload("client", &limits, 0).is_ok());
+        assert!(tracker.record_upload("client", &limits, u64::MAX).is_err());
+    }
+
+    #[test]
+    fn render_time_is_limited(){
+        let tracker = tracker();
+        let limits = QuotaLimits{ max_render_seconds_per_hour: Some(60), ..Default::default() };
+
+        tracker.record_render_time("client", Duration::from_secs(59));
+        drop(tracker.admit("client", &limits).unwrap());
+
+        tracker.record_render_time("client", Duration::from_secs(1));
+        assert!(tracker.admit("client", &limits).is_err());
+    }
+
+    #[test]
+    fn clients_are_tracked_separately(){
+        let tracker = tracker();
+        let limits = QuotaLimits{ max_concurrent_jobs: Some(1), ..Default::default() };
+
+        let _slot = tracker.admit("a", &limits).unwrap();
+        assert!(tracker.admit("a", &limits).is_err());
+        assert!(tracker.admit("b", &limits).is_ok());
+    }
+}
@@ -3,39 +3,57 @@ use std::io::Cursor;
 use std::path::{Path, PathBuf};
 use std::process::Command;
 use std::sync::Arc;
-use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::Duration;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
 use handlebars::{Context, DirectorySourceOptions, Handlebars, Helper, HelperResult, JsonRender, Output, RenderContext, RenderError, RenderErrorReason};
 use image::Luma;
 use qrcode::QrCode;
 use tokio::task::JoinSet;
+use tracing::{debug, error, info, info_span, warn, Instrument};
 use vb_exchange::{FilesOnMemoryOrHarddrive, NamedFile, RenderingError, RenderingRequest, RenderingResult, RenderingStatus};
 use vb_exchange::export_formats::{ExportStepData, PandocExportStep, RawExportStep, VivliostyleExportStep};
 use vb_exchange::projects::PreparedProject;
+use crate::retention;
+use crate::safe_path;
+use crate::self_check::check_step_available;
 use crate::settings::Settings;
 use crate::storage::Storage;
+use crate::template_cache;
+use crate::workspace::Workspace;
 
 pub async fn rendering_worker(storage: Arc<Storage>, settings: Arc<Settings>) {
-    let subthreads_num = Arc::new(AtomicU64::new(0));
-
     loop{
-        if subthreads_num.load(Ordering::Relaxed) >= settings.max_rendering_threads {
-            println!("Too many running subthreads, waiting for one to end.");
+        if storage.running_jobs.load(Ordering::Relaxed) >= settings.max_rendering_threads.get() {
+            tokio::time::sleep(Duration::from_millis(500)).await;
             continue;
         }
         let next_job = storage.request_queue.write().unwrap().pop_front();
 
         if let Some(job) = next_job{
-            println!("Found RenderingRequest.");
-
-            let render_request = Arc::new(job);
+            // Counted as running until the task ends, however it ends
+            let running = storage.start_job(&job);
+            let render_request = job.request;
+            let template_namespace = job.client.template_namespace.clone();
+            let client_name = job.client.client_name.clone();
+            let slot = job.slot;
+            // Uploads are removed with the task
+            let uploads = match &render_request.project_uploaded_files{
+                FilesOnMemoryOrHarddrive::Harddrive(path) => Some(Workspace::adopt(path.clone())),
+                _ => None
+            };
             let storage_cpy = Arc::clone(&storage);
-            let subthreads_num_cpy = Arc::clone(&subthreads_num);
+            let settings_cpy = Arc::clone(&settings);
+            let span = info_span!("rendering_request", request_id = %render_request.request_id, template_id = %render_request.template_id, template_version_id = %render_request.template_version_id, client = %client_name);
 
             tokio::spawn(async move{
-                subthreads_num_cpy.fetch_add(1, Ordering::Relaxed);
+                info!(export_formats = ?render_request.export_formats, "Started rendering request.");
+                let _running = running;
+                // Keep the concurrent job of the client until rendering ended
+                let _slot = slot;
+                let _uploads = uploads;
+                let job_started = Instant::now();
 
                 // Get export formats to render
                 let mut export_formats_queue = render_request.export_formats.clone();
@@ -54,16 +72,24 @@ pub async fn rendering_worker(storage: Arc<Storage>, settings: Arc<Settings>) {
                     let export_format_slug = export_formats_queue.pop().unwrap();
                     let render_request_cpy = Arc::clone(&render_request);
                     let storage_cpy2 = storage_cpy.clone();
+                    let settings_cpy2 = settings_cpy.clone();
+                    let template_namespace_cpy = template_namespace.clone();
+                    let client_name_cpy = client_name.clone();
 
-                    println!("Debug: Started rendering export format {}.", &export_format_slug);
+                    let format_span = info_span!("export_format", slug = %export_format_slug);
 
                     join_set.spawn(tokio::task::spawn_blocking(move || {
-                        match render_export_format(export_format_slug, Arc::clone(&storage_cpy2), Arc::clone(&render_request_cpy)){
+                        let _span = format_span.entered();
+                        info!("Started rendering export format.");
+                        let started = Instant::now();
+                        let res = render_export_format(export_format_slug, Arc::clone(&storage_cpy2), Arc::clone(&render_request_cpy), &template_namespace_cpy, &client_name_cpy, &settings_cpy2);
+                        storage_cpy2.quotas.record_render_time(&client_name_cpy, started.elapsed());
+                        match res{
                             Ok(res) => {
                                 Ok(res)
                             },
                             Err(e) => {
-                                eprintln!("Couldn't render export format: {:?}", e);
+                                error!("Couldn't render export format: {:?}", e);
                                 Err(e)
                             }
                         }
@@ -71,116 +97,157 @@ pub async fn rendering_worker(storage: Arc<Storage>, settings: Arc<Settings>) {
                 }
 
                 while let Some(res) = join_set.join_next().await{
-                    if let Ok(res) = res{
-                        if let Ok(res) = res{
-                            match res{
-                                Ok(res) => {
-                                    results.push(res)
-                                }
-                                Err(e) => {
-                                    eprintln!("Export Format failed rendering: {:?}", e);
-                                    // Update status
-                                    if let Some(status) = storage_cpy.request_status.write().unwrap().get_mut(&render_request.request_id){
-                                        *status = RenderingStatus::Failed(e)
-                                    }
-                                    return;
-                                }
+                    let res = match res{
+                        Ok(Ok(res)) => res,
+                        Ok(Err(e)) => Err(RenderingError::Other(format!("Renderer crashed: {}", e))),
+                        Err(e) => Err(RenderingError::Other(format!("Renderer crashed: {}", e)))
+                    };
+                    match res{
+                        Ok(res) => {
+                            results.push(res)
+                        }
+                        Err(e) => {
+                            error!(elapsed_ms = job_started.elapsed().as_millis() as u64, "Rendering request failed: {:?}", e);
+                            storage_cpy.metrics.rendering_request("failed", Some(job_started.elapsed()));
+                            // Update status
+                            if let Some(status) = storage_cpy.request_status.write().unwrap().get_mut(&render_request.request_id){
+                                *status = RenderingStatus::Failed(e)
+                            }
+                            // The other export formats stop before their next export step, the guards are kept until they did
+                            if let Some(running) = storage_cpy.running_requests.read().unwrap().get(&render_request.request_id){
+                                running.cancelled.store(true, Ordering::Relaxed);
                             }
+                            while join_set.join_next().await.is_some(){}
+                            return;
                         }
                     }
                 }
 
                 let mut res_files : Vec<NamedFile> = vec![];
-                // Load result files into memory, then delete files
+                // Load result files into memory, the workspaces are deleted afterwards
                 for res in results{
                     for file in &res.files_to_transfer{
                         let content = match tokio::fs::read(file).await {
                             Ok(data) => data,
                             Err(e) => {
-                                eprintln!("Failed to read the file: {}", e);
+                                error!(file = %file.to_string_lossy(), "Failed to read the result file: {}", e);
                                 continue;
                             }
                         };
                         let filename = file.file_name().unwrap_or("invalid_filename".as_ref()).to_string_lossy().to_string();
                         res_files.push(NamedFile{ name: filename, content })
                     }
-                    for dir in &res.temp_dirs{
-                        if let Err(e) = tokio::fs::remove_dir_all(dir).await{
-                            eprintln!("Couldn't delete temp dir: {}. Keeping it for now.", e);
-                        }
-                    }
                 }
 
-                // Delete project uploads
-                if let FilesOnMemoryOrHarddrive::Harddrive(path) = &render_request.project_uploaded_files{
-                    if let Err(e) = tokio::fs::remove_dir_all(path).await{
-                        eprintln!("Couldn't delete project uploads dir: {}.", e);
-                    }
-                }
+                storage_cpy.metrics.bytes_sent(res_files.iter().map(|file| file.content.len() as u64).sum());
 
                 // Update status
                 if let Some(status) = request_status_storage.write().unwrap().get_mut(&render_request.request_id){
                     *status = RenderingStatus::Finished(RenderingResult{files: res_files})
                 }
-
-                subthreads_num_cpy.fetch_sub(1, Ordering::Relaxed);
-            });
-
-        }else{
-            println!("No Rendering Job in queue");
+                storage_cpy.job_durations.record(job_started.elapsed());
+                storage_cpy.metrics.rendering_request("finished", Some(job_started.elapsed()));
+                info!(elapsed_ms = job_started.elapsed().as_millis() as u64, "Finished rendering request.");
+            }.instrument(span));
         }
 
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
 }
 
-#[derive(Clone)]
 pub struct ExportFormatRenderingResult{
     /// Paths to all files that should be transferred to main server
-    files_to_transfer: Vec<PathBuf>,
-    /// Paths to all created temp directories to delete after file transfer
-    temp_dirs: Vec<PathBuf>,
+    pub files_to_transfer: Vec<PathBuf>,
+    /// Log of all export steps
+    pub log: String,
+    /// Temp directories of all export steps, deleted once the result is dropped
+    _temp_dirs: Vec<Workspace>,
 }
 
-pub fn render_export_format(slug: String, storage: Arc<Storage>, request: Arc<RenderingRequest>) -> Result<ExportFormatRenderingResult, RenderingError>{
+/// Renders all export steps of an export format
+///
+/// If the request fails and retention is enabled, its workspaces are kept for download by the main server.
+pub fn render_export_format(slug: String, storage: Arc<Storage>, request: Arc<RenderingRequest>, template_namespace: &str, client_name: &str, settings: &Settings) -> Result<ExportFormatRenderingResult, RenderingError>{
+    let mut workspaces = Vec::new();
+    let started = Instant::now();
+    let metrics = storage.metrics.clone();
+
+    let res = render_export_steps(&slug, storage, request.clone(), template_namespace, settings, &mut workspaces);
+    metrics.export_format(res.is_ok(), started.elapsed());
+
+    match res{
+        Ok((files_to_transfer, log)) => Ok(ExportFormatRenderingResult{
+            files_to_transfer,
+            log,
+            _temp_dirs: workspaces,
+        }),
+        Err(e) => {
+            if retention::should_retain(settings, &request){
+                if let Err(io_err) = retention::retain(settings, request.request_id, client_name, &slug, workspaces, &e){
+                    error!("Couldn't retain failed workspaces: {}", io_err);
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Renders the export steps one after another, each in a new workspace pushed to `temp_directories`
+///
+/// Returns the files to keep of the last step and the rendering log.
+fn render_export_steps(slug: &str, storage: Arc<Storage>, request: Arc<RenderingRequest>, template_namespace: &str, settings: &Settings, temp_directories: &mut Vec<Workspace>) -> Result<(Vec<PathBuf>, String), RenderingError>{
     let mut rendering_log = String::new();
+    // The slug is client-supplied and used as a directory name if the workspaces are retained
+    safe_path::relative(slug)?;
 
-    let export_format = match storage.template_storage.read().unwrap().get(&request.template_id){
+    let export_format = match storage.template_storage.read().unwrap().get(&(template_namespace.to_string(), request.template_id)){
         Some(template) => {
-            match template.export_formats.get(&slug){
+            match template.export_formats(request.template_version_id).and_then(|export_formats| export_formats.get(slug)){
                 Some(ef) => ef.clone(),
                 None => {
-                    eprintln!("Couldn't find export format {}.", slug);
+                    error!(slug = %slug, template_version_id = %request.template_version_id, "Couldn't find export format in cached template version.");
                     return Err(RenderingError::TemplateNotFound)
                 }
             }
         },
         None => {
-            eprintln!("Couldn't find template {} in storage.", &request.template_id);
+            error!(template_id = %request.template_id, "Couldn't find template in storage.");
             return Err(RenderingError::TemplateNotFound)
         }
     };
 
     rendering_log.push_str(&format!("Started rendering export format {}.", export_format.slug));
+    safe_path::relative(&export_format.slug)?;
 
-    let mut temp_directories : Vec<PathBuf> = Vec::new();
     let mut files_to_copy_into_next_export_steps: Vec<PathBuf> = Vec::new();
+    // Not registered for local renderings
+    let running = storage.running_requests.read().unwrap().get(&request.request_id).cloned();
 
     for export_step in export_format.export_steps{
+        let _span = info_span!("export_step", step = %export_step.name).entered();
+        if let Some(running) = &running{
+            if running.cancelled.load(Ordering::Relaxed){
+                info!("Cancelled before export step.");
+                return Err(RenderingError::Other("Cancelled by operator.".to_string()));
+            }
+            running.current_steps.lock().unwrap().insert(slug.to_string(), export_step.name.clone());
+        }
+        debug!("Started rendering export step.");
         rendering_log.push_str(&format!("Started rendering export step {}.", export_step.name));
         let files_to_keep = export_step.files_to_keep;
 
         // Prepare temp directory
-        let temp_directory = match prepare_temp_directory(request.clone(), &export_format.slug){
-            Ok(temp_id) => temp_id,
+        let workspace = match prepare_temp_directory(request.clone(), &export_format.slug, template_namespace, settings){
+            Ok(workspace) => workspace,
             Err(e) => {
-                eprintln!("Couldn't prepare temp directory: {}", e);
+                error!("Couldn't prepare temp directory: {}", e);
                 return Err(RenderingError::Other("IO Error preparing temp directory.".to_string()));
             }
         };
-        temp_directories.push(temp_directory.clone());
+        let temp_directory = workspace.path().clone();
+        temp_directories.push(workspace);
         rendering_log.push_str("Prepared temporary directory.");
-        println!("Debug: Prepared temporary directory under {}.", &temp_directory.to_string_lossy());
+        debug!(path = %temp_directory.to_string_lossy(), "Prepared temporary directory.");
 
         // Copy files from previous export step if any
         if files_to_copy_into_next_export_steps.len() > 0{
@@ -197,18 +264,24 @@ pub fn render_export_format(slug: String, storage: Arc<Storage>, request: Arc<Re
 
         }
 
-        let res = match export_step.data{
-            ExportStepData::Raw(raw) => render_raw_export_step(raw, &temp_directory, &request.prepared_project, &mut rendering_log),
-            ExportStepData::Vivliostyle(vivlio) => render_vivliostyle_export_step(vivlio, &temp_directory, &mut rendering_log),
-            ExportStepData::Pandoc(pan) => render_pandoc_export_step(pan, &temp_directory, &mut rendering_log)
+        if let Err(e) = check_step_available(&storage, &export_step.data){
+            return Err(e);
+        }
+
+        let started = Instant::now();
+        let (engine, res) = match export_step.data{
+            ExportStepData::Raw(raw) => ("raw", render_raw_export_step(raw, &temp_directory, &request.prepared_project, &mut rendering_log)),
+            ExportStepData::Vivliostyle(vivlio) => ("vivliostyle", render_vivliostyle_export_step(vivlio, &temp_directory, settings, &mut rendering_log)),
+            ExportStepData::Pandoc(pan) => ("pandoc", render_pandoc_export_step(pan, &temp_directory, settings, &mut rendering_log))
         };
+        storage.metrics.export_step(engine, res.is_ok(), started.elapsed());
 
         if let Err(e) = res{
             return Err(e);
         }
 
         for file in files_to_keep{
-            let path = temp_directory.clone().join(PathBuf::from(file.clone()));
+            let path = safe_path::confine(&temp_directory, &file)?;
             if !path.exists(){
                 return Err(RenderingError::MissingExpectedFileToKeep(file, rendering_log))
             }else{
@@ -217,59 +290,63 @@ pub fn render_export_format(slug: String, storage: Arc<Storage>, request: Arc<Re
         }
     }
 
-    let res = ExportFormatRenderingResult{
-        files_to_transfer: files_to_copy_into_next_export_steps,
-        temp_dirs: temp_directories,
-    };
-
-    Ok(res)
+    Ok((files_to_copy_into_next_export_steps, rendering_log))
 }
 
-/// Prepares a new directory inside temp, copying all global_assets and assets of the given export format to this folder
+/// Prepares a new directory inside the job work dir, copying all global_assets and assets of the given export format to this folder
 ///
-/// Returns a PathBuf to the temp directory
-fn prepare_temp_directory(request: Arc<RenderingRequest>, export_format_slug: &str) -> io::Result<PathBuf>{
-    // Prepare temp dir:
-    // Create new dir in temp/
-    let random_id = uuid::Uuid::new_v4();
-    let temp_dir_path = format!("temp/{}", &random_id);
-    let temp_dir_path = Path::new(&temp_dir_path);
-    fs::create_dir(temp_dir_path)?;
-
-    let base_dir = format!("templates/{}", &request.template_version_id);
-    let base_dir = Path::new(&base_dir);
+/// Returns the workspace, removed again if preparing it fails
+fn prepare_temp_directory(request: Arc<RenderingRequest>, export_format_slug: &str, template_namespace: &str, settings: &Settings) -> io::Result<Workspace>{
+    // Create new dir in job work dir
+    let workspace = Workspace::create(settings)?;
+
+    let base_dir = template_cache::template_dir(settings, template_namespace, request.template_version_id);
+    let uploads = match &request.project_uploaded_files{
+        FilesOnMemoryOrHarddrive::Harddrive(path) => Some(path.as_path()),
+        _ => None
+    };
+    populate_workspace(workspace.path(), &base_dir, export_format_slug, uploads)?;
 
+    Ok(workspace)
+}
+
+/// Copies the global assets, the files of the export format & the project uploads of a template directory into a workspace
+pub fn populate_workspace(workspace_dir: &Path, template_dir: &Path, export_format_slug: &str, uploads: Option<&Path>) -> io::Result<()>{
     // Copy global assets
-    copy_dir_all(base_dir.join("assets"), temp_dir_path.join("global_assets"))?;
+    copy_dir_all(template_dir.join("assets"), workspace_dir.join("global_assets"))?;
 
     // Copy export format specific assets
-    let dir_content = fs::read_dir(base_dir.join(format!("formats/{}", export_format_slug)))?;
+    let dir_content = fs::read_dir(template_dir.join(format!("formats/{}", export_format_slug)))?;
 
     // Copy project uploads
-    if let vb_exchange::FilesOnMemoryOrHarddrive::Harddrive(path) = &request.project_uploaded_files{
-        copy_dir_all(path, temp_dir_path.join("uploads"))?;
+    if let Some(uploads) = uploads{
+        copy_dir_all(uploads, workspace_dir.join("uploads"))?;
     }
 
     for entry in dir_content{
         let entry = entry?;
         let ty = entry.file_type()?;
-        if ty.is_dir(){
-            copy_dir_all(entry.path(), temp_dir_path)?;
+        if ty.is_symlink(){
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Refusing to copy symlink {}", entry.path().to_string_lossy())));
+        }else if ty.is_dir(){
+            copy_dir_all(entry.path(), workspace_dir)?;
         }else{
-            fs::copy(entry.path(), temp_dir_path.join(entry.file_name()))?;
+            fs::copy(entry.path(), workspace_dir.join(entry.file_name()))?;
         }
     }
 
-    Ok(PathBuf::from(temp_dir_path))
+    Ok(())
 }
 
 /// Copies all contents from src dir to dst dir, creating the dst dir if necessary
-fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
+pub fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()> {
     fs::create_dir_all(&dst)?;
     for entry in fs::read_dir(src)? {
         let entry = entry?;
         let ty = entry.file_type()?;
-        if ty.is_dir() {
+        if ty.is_symlink() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Refusing to copy symlink {}", entry.path().to_string_lossy())));
+        } else if ty.is_dir() {
             copy_dir_all(entry.path(), dst.as_ref().join(entry.file_name()))?;
         } else {
             fs::copy(entry.path(), dst.as_ref().join(entry.file_name()))?;
@@ -278,6 +355,9 @@ fn copy_dir_all(src: impl AsRef<Path>, dst: impl AsRef<Path>) -> io::Result<()>
     Ok(())
 }
 
+/// Helpers registered in addition to the handlebars built-ins
+pub const CUSTOM_HELPERS: &[&str] = &["qrcode"];
+
 pub fn render_raw_export_step(step: RawExportStep, temp_dir: &PathBuf, prepared_project: &PreparedProject, rendering_log: &mut String) -> Result<(), RenderingError>{
     let mut handlebars = Handlebars::new();
 
@@ -285,24 +365,27 @@ pub fn render_raw_export_step(step: RawExportStep, temp_dir: &PathBuf, prepared_
     dir_options.tpl_extension = String::from(".hbs.html");
 
     if let Err(e) = handlebars.register_templates_directory(temp_dir, dir_options){
-        eprintln!("Couldn't register templates: {}", e);
+        warn!("Couldn't register templates: {}", e);
         return Err(RenderingError::CouldntLoadHandlebarTemplates(e.to_string()))
     }
 
     // Add custom handler for qr codes
     handlebars.register_helper("qrcode", Box::new(handlebars_qrcode_helper));
 
+    safe_path::relative(&step.entry_point)?;
+    let output_file = safe_path::confine(temp_dir, &step.output_file)?;
+
     rendering_log.push_str("Starting handlebars rendering.");
     match handlebars.render(&step.entry_point.replace(".hbs.html", ""), prepared_project){
         Ok(res) => {
-            if let Err(e) = fs::write(temp_dir.join(PathBuf::from(step.output_file)), res){
-                eprintln!("Couldn't write rendered template: {}", e);
+            if let Err(e) = fs::write(output_file, res){
+                error!("Couldn't write rendered template: {}", e);
                 rendering_log.push_str(&format!("Couldn't write rendered template: {}", e));
                 return Err(RenderingError::HandlebarsRenderingFailed(rendering_log.clone()))
             }
         },
         Err(e) => {
-            eprintln!("Handlebars rendering failed: {}", e);
+            warn!("Handlebars rendering failed: {}", e);
             rendering_log.push_str(&format!("Handlebars rendering failed: {}", e));
             return Err(RenderingError::HandlebarsRenderingFailed(rendering_log.clone()));
         }
@@ -319,7 +402,7 @@ fn handlebars_qrcode_helper(h: &Helper, _: &Handlebars, _: &Context, _rc: &mut R
     let qr_code = match QrCode::new(val.to_string()){
         Ok(qr_code) => qr_code,
         Err(e) => {
-            eprintln!("Couldn't create qr code: {}", e);
+            warn!("Couldn't create qr code: {}", e);
             return Err(RenderError::from(RenderErrorReason::Other(format!("Couldn't create qr code: {}", e))));
         }
     };
@@ -330,7 +413,7 @@ fn handlebars_qrcode_helper(h: &Helper, _: &Handlebars, _: &Context, _rc: &mut R
     match image.write_to(&mut buf, image::ImageFormat::Jpeg){
         Ok(_) => {}
         Err(e) => {
-            eprintln!("Couldn't write qr code to buffer: {}", e);
+            error!("Couldn't write qr code to buffer: {}", e);
             return Err(RenderError::from(RenderErrorReason::Other(format!("Couldn't write qr code to buffer: {}", e))));
         }
     }
@@ -340,27 +423,18 @@ fn handlebars_qrcode_helper(h: &Helper, _: &Handlebars, _: &Context, _rc: &mut R
     Ok(())
 }
 
-pub fn render_vivliostyle_export_step(step: VivliostyleExportStep, temp_dir: &PathBuf, rendering_log: &mut String) -> Result<(), RenderingError>{
-    // Start bubblewrap
-    let mut command = Command::new("bwrap");
-
-    command.arg("--unshare-all").arg("--tmpfs").arg("/tmp").arg("--ro-bind").arg("/lib").arg("/lib").arg("--ro-bind").arg("/lib64").arg("/lib64").arg("--ro-bind").arg("/usr/lib").arg("/usr/lib").arg("--proc").arg("/proc").arg("--dev").arg("/dev");
-
-    if Path::new("/usr/share/fonts").exists(){
-        command.arg("--ro-bind").arg("/usr/share/fonts").arg("/usr/share/fonts");
-    }else {
-        if Path::new("/usr/local/share/fonts").exists() {
-            command.arg("--ro-bind").arg("/usr/local/share/fonts").arg("/usr/share/fonts/");
-        }
-    }
+pub fn render_vivliostyle_export_step(step: VivliostyleExportStep, temp_dir: &PathBuf, settings: &Settings, rendering_log: &mut String) -> Result<(), RenderingError>{
+    let input_file = safe_path::relative(&step.input_file)?;
+    let output_file = safe_path::relative(&step.output_file)?;
+    let mut command = vivliostyle_sandbox_command(temp_dir, settings);
 
-    command.arg("--bind").arg(temp_dir).arg("/data").arg("--ro-bind").arg("rendering-envs/vivliostyle").arg("/env").arg("/env/node").arg("/env/node_modules/.bin/vivliostyle").arg("build").arg(format!("/data/{}", step.input_file));
+    command.arg("build").arg(Path::new("/data").join(input_file));
 
     if step.press_ready{
         command.arg("-p");
     }
 
-    command.arg("-o").arg(format!("/data/{}", step.output_file));
+    command.arg("-o").arg(Path::new("/data").join(output_file));
     command.arg("--executable-browser").arg("/env/chromium/chrome");
 
     match command.output() {
@@ -379,23 +453,22 @@ pub fn render_vivliostyle_export_step(step: VivliostyleExportStep, temp_dir: &Pa
     }
 }
 
-pub fn render_pandoc_export_step(step: PandocExportStep, temp_dir: &PathBuf, rendering_log: &mut String) -> Result<(), RenderingError>{
-    println!("Started rendering pandoc export step.");
-    let mut command = Command::new("bwrap");
-
-    command.arg("--unshare-all").arg("--bind").arg(temp_dir).arg("/data").arg("--ro-bind").arg("rendering-envs/pandoc").arg("/env").arg("/env/pandoc");
+pub fn render_pandoc_export_step(step: PandocExportStep, temp_dir: &PathBuf, settings: &Settings, rendering_log: &mut String) -> Result<(), RenderingError>{
+    let input_file = safe_path::relative(&step.input_file)?;
+    let output_file = safe_path::relative(&step.output_file)?;
+    let mut command = pandoc_sandbox_command(temp_dir, settings);
 
-    command.arg("-o").arg(format!("/data/{}", step.output_file)).arg("-t").arg(step.output_format.to_string());
+    command.arg("-o").arg(Path::new("/data").join(output_file)).arg("-t").arg(step.output_format.to_string());
     command.arg("-f").arg(step.input_format.to_string());
 
     if let Some(shift) = step.shift_heading_level_by{
         command.arg(format!("--shift-heading-level-by={}", shift));
     }
     if let Some(metadata_file) = step.metadata_file{
-        command.arg(format!("--metadata-file={}", metadata_file));
+        command.arg(format!("--metadata-file={}", safe_path::relative(&metadata_file)?.to_string_lossy()));
     }
     if let Some(epub_cover_image_path) = step.epub_cover_image_path{
-        command.arg(format!("--epub-cover-image={}", epub_cover_image_path));
+        command.arg(format!("--epub-cover-image={}", safe_path::relative(&epub_cover_image_path)?.to_string_lossy()));
     }
     if let Some(epub_title_page) = step.epub_title_page{
         if epub_title_page{
@@ -405,15 +478,15 @@ pub fn render_pandoc_export_step(step: PandocExportStep, temp_dir: &PathBuf, ren
         }
     }
     if let Some(epub_metadata_file) = step.epub_metadata_file{
-        command.arg(format!("--epub-metadata={}", epub_metadata_file));
+        command.arg(format!("--epub-metadata={}", safe_path::relative(&epub_metadata_file)?.to_string_lossy()));
     }
     if let Some(epub_embed_fonts) = step.epub_embed_fonts{
         for font in epub_embed_fonts{
-            command.arg(format!("--epub-embed-font={}", font));
+            command.arg(format!("--epub-embed-font={}", safe_path::relative(&font)?.to_string_lossy()));
         }
     }
 
-    command.arg(format!("data/{}", step.input_file));
+    command.arg(Path::new("data").join(input_file));
 
     match command.output() {
         Ok(res1) => {
@@ -421,7 +494,7 @@ pub fn render_pandoc_export_step(step: PandocExportStep, temp_dir: &PathBuf, ren
             let stderr = String::from_utf8(res1.stderr).unwrap_or("".to_string());
             let res = format!("Pandoc ran. stdout: {:?}, stderr: {:?}", &stdout, &stderr);
             rendering_log.push_str(&res);
-            println!("{}", res);
+            debug!(stdout = %stdout, stderr = %stderr, "Pandoc ran.");
             Ok(())
         },
         Err(e) => {
@@ -429,4 +502,35 @@ pub fn render_pandoc_export_step(step: PandocExportStep, temp_dir: &PathBuf, ren
             Err(RenderingError::PandocConversionFailed(rendering_log.clone()))
         }
     }
-}
\ No newline at end of file
+}
+
+/// Returns the font directory of the host to mount into the sandbox, if any
+pub fn host_font_dir() -> Option<&'static str>{
+    ["/usr/share/fonts", "/usr/local/share/fonts"].into_iter().find(|dir| Path::new(dir).exists())
+}
+
+/// Builds the bubblewrap command running node from the vivliostyle env, with temp_dir mounted at /data
+///
+/// Add the arguments for node (e.g. the vivliostyle cli) to the returned command.
+pub fn vivliostyle_sandbox_command(temp_dir: &Path, settings: &Settings) -> Command{
+    let mut command = Command::new("bwrap");
+
+    command.arg("--unshare-all").arg("--die-with-parent").arg("--tmpfs").arg("/tmp").arg("--ro-bind").arg("/lib").arg("/lib").arg("--ro-bind").arg("/lib64").arg("/lib64").arg("--ro-bind").arg("/usr/lib").arg("/usr/lib").arg("--proc").arg("/proc").arg("--dev").arg("/dev");
+
+    if let Some(font_dir) = host_font_dir(){
+        command.arg("--ro-bind").arg(font_dir).arg("/usr/share/fonts");
+    }
+
+    command.arg("--bind").arg(temp_dir).arg("/data").arg("--ro-bind").arg(&settings.vivliostyle_env_path).arg("/env").arg("/env/node").arg("/env/node_modules/.bin/vivliostyle");
+
+    command
+}
+
+/// Builds the bubblewrap command running pandoc from the pandoc env, with temp_dir mounted at /data
+pub fn pandoc_sandbox_command(temp_dir: &Path, settings: &Settings) -> Command{
+    let mut command = Command::new("bwrap");
+
+    command.arg("--unshare-all").arg("--die-with-parent").arg("--bind").arg(temp_dir).arg("/data").arg("--ro-bind").arg(&settings.pandoc_env_path).arg("/env").arg("/env/pandoc");
+
+    command
+}
@@ -0,0 +1,89 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use vb_exchange::{RenderingError, RenderingRequest};
+use tracing::info;
+use crate::safe_path;
+use crate::settings::Settings;
+use crate::workspace::Workspace;
+
+/// File inside a retained request directory naming the client allowed to download it
+const OWNER_FILE: &str = "owner";
+
+/// Whether workspaces of this request are kept if it fails, either globally or requested by the main server
+pub fn should_retain(settings: &Settings, request: &RenderingRequest) -> bool{
+    settings.retain_failed_workspaces || request.retain_failed_workspace
+}
+
+fn request_dir(settings: &Settings, request_id: uuid::Uuid) -> PathBuf{
+    Path::new(&settings.retained_workspace_path).join(request_id.to_string())
+}
+
+/// Moves the workspaces of a failed export format to the retained workspace dir, together with the error
+///
+/// They are kept as `<request id>/<export format>/<step number>` until the janitor removes them after failed_workspace_retention seconds.
+pub fn retain(settings: &Settings, request_id: uuid::Uuid, client_name: &str, export_format_slug: &str, workspaces: Vec<Workspace>, error: &RenderingError) -> io::Result<()>{
+    let request_dir = request_dir(settings, request_id);
+    let slug = safe_path::relative(export_format_slug).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    let format_dir = request_dir.join(slug);
+    fs::create_dir_all(&format_dir)?;
+    fs::write(request_dir.join(OWNER_FILE), client_name)?;
+
+    for (step, workspace) in workspaces.iter().enumerate(){
+        move_dir(workspace.path(), &format_dir.join(step.to_string()))?;
+    }
+    fs::write(format_dir.join("error.txt"), format!("{:?}", error))?;
+
+    info!(request_id = %request_id, path = %format_dir.to_string_lossy(), "Retained failed workspaces.");
+    Ok(())
+}
+
+/// Renames a directory, or copies & removes it if job_work_path and retained_workspace_path are on different filesystems
+fn move_dir(src: &Path, dst: &Path) -> io::Result<()>{
+    match fs::rename(src, dst){
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {
+            copy_tree(src, dst)?;
+            fs::remove_dir_all(src)
+        },
+        res => res
+    }
+}
+
+/// Copies a directory recursively, keeping symlinks created by renderers as links
+fn copy_tree(src: &Path, dst: &Path) -> io::Result<()>{
+    fs::create_dir_all(dst)?;
+    for entry in fs::read_dir(src)?{
+        let entry = entry?;
+        let ty = entry.file_type()?;
+        let target = dst.join(entry.file_name());
+        if ty.is_symlink(){
+            std::os::unix::fs::symlink(fs::read_link(entry.path())?, &target)?;
+        }else if ty.is_dir(){
+            copy_tree(&entry.path(), &target)?;
+        }else{
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Packs the retained workspaces of a rendering request into a tar.gz archive
+///
+/// Returns None if nothing was retained for the request or it belongs to another client.
+pub fn archive(settings: &Settings, request_id: uuid::Uuid, client_name: &str) -> io::Result<Option<Vec<u8>>>{
+    let request_dir = request_dir(settings, request_id);
+    match fs::read_to_string(request_dir.join(OWNER_FILE)){
+        Ok(owner) if owner == client_name => {},
+        Ok(_) => return Ok(None),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    }
+
+    let mut archive = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+    // Keep symlinks created by renderers as links instead of following them
+    archive.follow_symlinks(false);
+    archive.append_dir_all(request_id.to_string(), &request_dir)?;
+    Ok(Some(archive.into_inner()?.finish()?))
+}
@@ -0,0 +1,89 @@
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::{TlsConnector, TlsStream};
+use tracing::{info, warn};
+use vb_exchange::Message;
+use crate::capabilities::collect_capabilities;
+use crate::connection_handler::authorize_peer;
+use crate::limits;
+use crate::limits::LimitedReader;
+use crate::protocol;
+use crate::session::run_session;
+use crate::settings::Settings;
+use crate::storage::Storage;
+use crate::tls::TlsConfigs;
+
+/// Keeps an outbound connection to the configured main server open, reconnecting with exponential backoff
+///
+/// Used for rendering servers without inbound reachability. The main server sends its jobs over this connection.
+#[tracing::instrument(name = "reverse_connection", skip_all, fields(main_server = %main_server_address))]
+pub async fn run_reverse_connection(main_server_address: String, storage: Arc<Storage>, settings: Arc<Settings>, tls_configs: Arc<TlsConfigs>){
+    let max_backoff = settings.reverse_connect_max_backoff;
+    let mut backoff = Duration::from_secs(1);
+
+    loop{
+        // Take the current config on every attempt, it may have been reloaded
+        let connector = TlsConnector::from(tls_configs.client_config());
+        let res = connect_and_serve(&main_server_address, &connector, storage.clone(), settings.clone()).await;
+        if storage.is_draining(){
+            info!("Draining, not reconnecting to main server.");
+            return;
+        }
+        match res{
+            Ok(()) => {
+                info!("Connection to main server closed, reconnecting.");
+                backoff = Duration::from_secs(1);
+            },
+            Err(e) => {
+                warn!(retry_in_s = backoff.as_secs(), "Couldn't connect to main server: {}", e);
+            }
+        }
+
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// Connects to the main server, registers this rendering server and serves a session until the connection closes
+async fn connect_and_serve(address: &str, connector: &TlsConnector, storage: Arc<Storage>, settings: Arc<Settings>) -> Result<(), String>{
+    let server_name = settings.main_server_name.clone().unwrap_or_else(|| host_of(address).to_string());
+    let server_name = ServerName::try_from(server_name).map_err(|e| format!("Invalid main server name: {}", e))?;
+
+    let tcp_stream = TcpStream::connect(address).await.map_err(|e| e.to_string())?;
+    let tls_stream = connector.connect(server_name, tcp_stream).await.map_err(|e| format!("TLS error: {}", e))?;
+    let tls_stream: TlsStream<TcpStream> = tls_stream.into();
+    storage.metrics.connection("reverse");
+    let client = Arc::new(authorize_peer(&tls_stream, &settings)?);
+    let mut tls_stream = LimitedReader::new(tls_stream, settings.max_message_bytes);
+
+    vb_exchange::send_message(&mut tls_stream, Message::Hello(protocol::offered_hello())).await.map_err(|_| "Couldn't send hello.".to_string())?;
+    let protocol = match limits::read_message(&mut tls_stream).await{
+        Ok(Message::HelloResponse(hello)) => protocol::negotiate(&hello)?,
+        Ok(Message::CommunicationError(e)) => return Err(format!("Main server refused connection: {:?}", e)),
+        Ok(_) => return Err("Received unexpected Message type.".to_string()),
+        Err(e) => return Err(format!("Error occured reading hello: {}", e))
+    };
+    if !protocol.supports(protocol::FEATURE_SESSIONS){
+        return Err("Main server doesn't support sessions.".to_string());
+    }
+
+    // Register with our capabilities, the main server then uses this connection like a session it opened
+    vb_exchange::send_message(&mut tls_stream, Message::Capabilities(collect_capabilities(&storage, &settings))).await.map_err(|_| "Couldn't register at main server.".to_string())?;
+    info!("Registered at main server.");
+
+    let _connection = storage.open_connection();
+    run_session(tls_stream.into_inner(), storage, settings, protocol, client).await;
+
+    Ok(())
+}
+
+/// Returns the host part of a host:port address, without the brackets of IPv6 addresses
+fn host_of(address: &str) -> &str{
+    let host = match address.rsplit_once(':'){
+        Some((host, _)) => host,
+        None => address
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
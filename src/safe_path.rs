@@ -0,0 +1,198 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use vb_exchange::{FilesOnMemoryOrHarddrive, RenderingError};
+
+/// Template- or client-supplied path that would leave its directory
+#[derive(Debug)]
+pub struct PathViolation{
+    pub path: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for PathViolation{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result{
+        write!(f, "Rejected path {:?}: {}", self.path, self.reason)
+    }
+}
+
+impl From<PathViolation> for RenderingError{
+    fn from(violation: PathViolation) -> Self{
+        RenderingError::PathViolation(violation.to_string())
+    }
+}
+
+fn violation(path: impl AsRef<Path>, reason: &'static str) -> PathViolation{
+    PathViolation{ path: path.as_ref().to_string_lossy().to_string(), reason }
+}
+
+/// Checks that a path is relative and doesn't contain `..`, so it stays inside whatever directory it's joined onto
+pub fn relative(path: &str) -> Result<PathBuf, PathViolation>{
+    let mut normalized = PathBuf::new();
+    for component in Path::new(path).components(){
+        match component{
+            Component::Normal(part) => normalized.push(part),
+            Component::CurDir => {},
+            Component::ParentDir => return Err(violation(path, "contains \"..\"")),
+            Component::RootDir | Component::Prefix(_) => return Err(violation(path, "is absolute")),
+        }
+    }
+    if normalized.as_os_str().is_empty(){
+        return Err(violation(path, "is empty"));
+    }
+    Ok(normalized)
+}
+
+/// Joins a path onto a base directory, rejecting paths leaving it directly or through a symlink
+pub fn confine(base: &Path, path: &str) -> Result<PathBuf, PathViolation>{
+    let relative = relative(path)?;
+
+    let mut current = base.to_path_buf();
+    for part in relative.iter(){
+        current.push(part);
+        match fs::symlink_metadata(&current){
+            Ok(meta) if meta.file_type().is_symlink() => return Err(violation(path, "runs through a symlink")),
+            Ok(_) => {},
+            // The rest of the path doesn't exist yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(_) => return Err(violation(path, "couldn't be checked")),
+        }
+    }
+    Ok(base.join(relative))
+}
+
+/// Checks that a directory written from uploads or template data doesn't contain any symlinks
+///
+/// Entries that can't be read are rejected as well, as they can't be checked.
+pub fn check_tree(dir: &Path) -> Result<(), PathViolation>{
+    let entries = match fs::read_dir(dir){
+        Ok(entries) => entries,
+        Err(_) => return Err(violation(dir, "couldn't be read")),
+    };
+    for entry in entries{
+        let entry = match entry{
+            Ok(entry) => entry,
+            Err(_) => return Err(violation(dir, "couldn't be read")),
+        };
+        let ty = match entry.file_type(){
+            Ok(ty) => ty,
+            Err(_) => return Err(violation(entry.path(), "couldn't be read")),
+        };
+        if ty.is_symlink(){
+            return Err(violation(entry.path(), "is a symlink"));
+        }
+        if ty.is_dir(){
+            check_tree(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Checks the paths of uploaded files or template data sent by a main server before they are written to disk
+///
+/// Files on harddrive are rejected, as they would point into the filesystem of the rendering server.
+pub fn check_files(files: &FilesOnMemoryOrHarddrive) -> Result<(), PathViolation>{
+    match files{
+        FilesOnMemoryOrHarddrive::Memory(mem) => {
+            for path in mem.paths(){
+                relative(&path)?;
+            }
+            Ok(())
+        },
+        FilesOnMemoryOrHarddrive::Harddrive(path) => Err(violation(path, "refers to the filesystem of the rendering server")),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn temp_dir() -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("vb-safe-path-test-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn relative_accepts_nested_paths(){
+        assert_eq!(relative("formats/pdf/index.hbs.html").unwrap(), PathBuf::from("formats/pdf/index.hbs.html"));
+        assert_eq!(relative("./assets//style.css").unwrap(), PathBuf::from("assets/style.css"));
+    }
+
+    #[test]
+    fn relative_rejects_absolute_paths(){
+        assert!(relative("/etc/passwd").is_err());
+        assert!(relative("/").is_err());
+    }
+
+    #[test]
+    fn relative_rejects_parent_dirs(){
+        assert!(relative("..").is_err());
+        assert!(relative("../outside").is_err());
+        assert!(relative("assets/../../outside").is_err());
+        // Rejected even if it would stay inside
+        assert!(relative("assets/../style.css").is_err());
+    }
+
+    #[test]
+    fn relative_rejects_empty_paths(){
+        assert!(relative("").is_err());
+        assert!(relative(".").is_err());
+        assert!(relative("./").is_err());
+    }
+
+    #[test]
+    fn confine_joins_onto_base(){
+        let base = temp_dir();
+        fs::create_dir(base.join("assets")).unwrap();
+
+        assert_eq!(confine(&base, "assets/style.css").unwrap(), base.join("assets/style.css"));
+        assert_eq!(confine(&base, "new/dir/file").unwrap(), base.join("new/dir/file"));
+        assert!(confine(&base, "../outside").is_err());
+        assert!(confine(&base, "/etc/passwd").is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn confine_rejects_symlinks(){
+        let base = temp_dir();
+        let outside = temp_dir();
+        std::os::unix::fs::symlink(&outside, base.join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.join("file"), base.join("file_link")).unwrap();
+
+        assert!(confine(&base, "link").is_err());
+        assert!(confine(&base, "link/file").is_err());
+        // Dangling links are rejected as well, writing to them would create the target
+        assert!(confine(&base, "file_link").is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+        fs::remove_dir_all(&outside).unwrap();
+    }
+
+    #[test]
+    fn check_tree_finds_nested_symlinks(){
+        let base = temp_dir();
+        fs::create_dir_all(base.join("a/b")).unwrap();
+        fs::write(base.join("a/b/file"), "content").unwrap();
+        assert!(check_tree(&base).is_ok());
+
+        std::os::unix::fs::symlink("/etc/passwd", base.join("a/b/link")).unwrap();
+        assert!(check_tree(&base).is_err());
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn check_tree_rejects_dirs_it_cant_read(){
+        let base = temp_dir();
+        assert!(check_tree(&base.join("missing")).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn check_files_rejects_files_on_harddrive(){
+        assert!(check_files(&FilesOnMemoryOrHarddrive::Harddrive(PathBuf::from("/etc"))).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use serde::Deserialize;
+use vb_exchange::RenderingError;
+use vb_exchange::export_formats::ExportStepData;
+use crate::rendering::{host_font_dir, pandoc_sandbox_command, vivliostyle_sandbox_command};
+use crate::settings::Settings;
+use crate::storage::Storage;
+
+/// What to do if the self-check of a rendering engine fails on startup
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SelfCheckMode{
+    /// Refuse to start
+    Strict,
+    /// Start anyway, export steps using the failed engine fail immediately
+    Degrade,
+}
+
+/// Result of the startup self-check
+#[derive(Debug, Clone, Default)]
+pub struct EnvironmentReport{
+    /// Version string of bubblewrap, None if bwrap couldn't be run
+    pub bwrap_version: Option<String>,
+    /// Host font directory mounted into the sandbox
+    pub font_dir: Option<String>,
+    pub vivliostyle: EngineStatus,
+    pub pandoc: EngineStatus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct EngineStatus{
+    pub available: bool,
+    pub version: Option<String>,
+    /// Why the engine is unavailable
+    pub error: Option<String>,
+}
+
+impl EnvironmentReport{
+    /// Returns a list of all failed checks
+    pub fn problems(&self) -> Vec<String>{
+        let mut problems = Vec::new();
+        if self.bwrap_version.is_none(){
+            problems.push("bwrap couldn't be run. Install bubblewrap.".to_string());
+        }
+        if self.font_dir.is_none(){
+            problems.push("No font directory found (/usr/share/fonts or /usr/local/share/fonts).".to_string());
+        }
+        for (name, status) in [("vivliostyle", &self.vivliostyle), ("pandoc", &self.pandoc)]{
+            if let Some(e) = &status.error{
+                problems.push(format!("{} unavailable: {}", name, e));
+            }
+        }
+        problems
+    }
+}
+
+/// Checks bubblewrap, the font directories and each rendering environment by running a small smoke render per engine
+///
+/// Blocks until all engines finished rendering, call from a blocking thread.
+pub fn check_environment(settings: &Settings) -> EnvironmentReport{
+    let bwrap_version = match Command::new("bwrap").arg("--version").output(){
+        Ok(res) if res.status.success() => Some(String::from_utf8_lossy(&res.stdout).trim().to_string()),
+        _ => None
+    };
+
+    let mut report = EnvironmentReport{
+        bwrap_version,
+        font_dir: host_font_dir().map(|dir| dir.to_string()),
+        ..Default::default()
+    };
+
+    if report.bwrap_version.is_none(){
+        let error = Some("bwrap is missing".to_string());
+        report.vivliostyle.error = error.clone();
+        report.pandoc.error = error;
+        return report;
+    }
+
+    report.vivliostyle = check_engine(settings, "vivliostyle", check_vivliostyle);
+    report.pandoc = check_engine(settings, "pandoc", check_pandoc);
+
+    report
+}
+
+/// Version strings of bubblewrap and the rendering engines, without smoke renders
+///
+/// Engines that can't be run are reported as None.
+pub fn engine_versions(settings: &Settings) -> Vec<(&'static str, Option<String>)>{
+    let bwrap = run_checked(Command::new("bwrap").arg("--version")).ok();
+    let dir = std::env::temp_dir().join(format!("vb-version-{}", uuid::Uuid::new_v4()));
+    let (vivliostyle, pandoc) = match (&bwrap, fs::create_dir(&dir)){
+        (Some(_), Ok(_)) => (
+            run_checked(vivliostyle_sandbox_command(&dir, settings).arg("--version")).ok(),
+            run_checked(pandoc_sandbox_command(&dir, settings).arg("--version")).ok().map(|version| version.lines().next().unwrap_or_default().to_string())
+        ),
+        _ => (None, None)
+    };
+    let _ = fs::remove_dir_all(&dir);
+
+    vec![("bwrap", bwrap), ("vivliostyle", vivliostyle), ("pandoc", pandoc)]
+}
+
+/// Runs the check of an engine inside a fresh directory in the job work dir
+fn check_engine(settings: &Settings, name: &str, check: fn(&Path, &Settings) -> Result<String, String>) -> EngineStatus{
+    let dir = PathBuf::from(&settings.job_work_path).join(format!("self-check-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    if let Err(e) = fs::create_dir_all(&dir){
+        return EngineStatus{ available: false, version: None, error: Some(format!("Couldn't create directory for smoke render: {}", e)) };
+    }
+
+    let status = match check(&dir, settings){
+        Ok(version) => EngineStatus{ available: true, version: Some(version), error: None },
+        Err(e) => EngineStatus{ available: false, version: None, error: Some(e) }
+    };
+
+    let _ = fs::remove_dir_all(&dir);
+    status
+}
+
+fn check_vivliostyle(dir: &Path, settings: &Settings) -> Result<String, String>{
+    let node = Path::new(&settings.vivliostyle_env_path).join("node");
+    if !is_executable(&node){
+        return Err(format!("{} isn't executable", node.to_string_lossy()));
+    }
+
+    let version = run_checked(vivliostyle_sandbox_command(dir, settings).arg("--version"))?;
+
+    fs::write(dir.join("smoke.html"), "<!DOCTYPE html><html><head><title>Self-Check</title></head><body><p>Self-Check</p></body></html>").map_err(|e| format!("Couldn't write smoke test input: {}", e))?;
+    let output = run_checked(vivliostyle_sandbox_command(dir, settings).arg("build").arg("/data/smoke.html").arg("-o").arg("/data/smoke.pdf").arg("--executable-browser").arg("/env/chromium/chrome"))?;
+    if !dir.join("smoke.pdf").exists(){
+        return Err(format!("Smoke render didn't produce a pdf: {}", output));
+    }
+
+    Ok(version)
+}
+
+fn check_pandoc(dir: &Path, settings: &Settings) -> Result<String, String>{
+    let pandoc = Path::new(&settings.pandoc_env_path).join("pandoc");
+    if !is_executable(&pandoc){
+        return Err(format!("{} isn't executable", pandoc.to_string_lossy()));
+    }
+
+    let version = run_checked(pandoc_sandbox_command(dir, settings).arg("--version"))?;
+    let version = version.lines().next().unwrap_or_default().to_string();
+
+    fs::write(dir.join("smoke.md"), "# Self-Check").map_err(|e| format!("Couldn't write smoke test input: {}", e))?;
+    run_checked(pandoc_sandbox_command(dir, settings).arg("-f").arg("markdown").arg("-t").arg("html").arg("-o").arg("/data/smoke.html").arg("/data/smoke.md"))?;
+    if !dir.join("smoke.html").exists(){
+        return Err("Smoke render didn't produce a html file".to_string());
+    }
+
+    Ok(version)
+}
+
+/// Runs the command, returning its trimmed stdout or an error containing stderr if it didn't exit successfully
+fn run_checked(command: &mut Command) -> Result<String, String>{
+    match command.output(){
+        Ok(res) => {
+            if res.status.success(){
+                Ok(String::from_utf8_lossy(&res.stdout).trim().to_string())
+            }else{
+                Err(format!("exited with {}: {}", res.status, String::from_utf8_lossy(&res.stderr).trim()))
+            }
+        },
+        Err(e) => Err(format!("couldn't be started: {}", e))
+    }
+}
+
+fn is_executable(path: &Path) -> bool{
+    use std::os::unix::fs::PermissionsExt;
+
+    match fs::metadata(path){
+        Ok(meta) => meta.is_file() && meta.permissions().mode() & 0o111 != 0,
+        Err(_) => false
+    }
+}
+
+/// Fails export steps whose engine didn't pass the startup self-check
+pub fn check_step_available(storage: &Storage, step: &ExportStepData) -> Result<(), RenderingError>{
+    let environment = storage.environment.read().unwrap();
+    let (name, status) = match step{
+        ExportStepData::Raw(_) => return Ok(()),
+        ExportStepData::Vivliostyle(_) => ("Vivliostyle", &environment.vivliostyle),
+        ExportStepData::Pandoc(_) => ("Pandoc", &environment.pandoc),
+    };
+
+    if status.available{
+        Ok(())
+    }else{
+        Err(RenderingError::Other(format!("{} is unavailable on this rendering server: {}", name, status.error.clone().unwrap_or_default())))
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::mem::Discriminant;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot, Notify};
+use tokio_rustls::TlsStream;
+use tracing::{error, info, warn, Instrument};
+use vb_exchange::{CachedTemplatesResult, CommunicationError, Message, RenderingError, RenderingRequest, RenderingStatus, RetainedWorkspace, TaggedRenderingStatus, TemplateDataRequest, TemplateDataResult, TemplateDeltaRequest};
+use vb_exchange::export_formats::ExportFormat;
+use crate::authorization::{Action, Authorization};
+use crate::capabilities::collect_capabilities;
+use crate::connection_handler::{admit_request, cached_versions_to_advertise, register_template, retained_workspace_archive, save_uploads, write_template_data};
+use crate::limits;
+use crate::limits::LimitedReader;
+use crate::protocol;
+use crate::protocol::NegotiatedProtocol;
+use crate::settings::Settings;
+use crate::storage::Storage;
+use crate::template_cache;
+
+/// State shared between the reader loop, the status updates and the request tasks of one session
+struct Session{
+    storage: Arc<Storage>,
+    settings: Arc<Settings>,
+    protocol: NegotiatedProtocol,
+    /// Policy of the main server
+    client: Arc<Authorization>,
+    /// Messages to send to the main server
+    outgoing: mpsc::UnboundedSender<Message>,
+    /// Requests submitted in this session with the kind of the last status sent to the main server
+    requests: Mutex<HashMap<uuid::Uuid, Option<Discriminant<RenderingStatus>>>>,
+    /// Template versions requested from the main server, waiting for their TemplateDataResult or TemplateDeltaResult
+    pending_templates: Mutex<HashMap<uuid::Uuid, oneshot::Sender<Message>>>,
+    /// Templates are requested one at a time, so requests for the same new version don't download it twice
+    template_lock: tokio::sync::Mutex<()>,
+    /// Set when the session ends, requests queued afterwards are cancelled right away
+    closed: AtomicBool,
+}
+
+/// Runs a multiplexed session until the main server sends [Message::CloseSession], the connection breaks
+/// or the server drains and all requests of the session were answered
+///
+/// The main server may send any number of rendering requests. Status updates are only sent on changes, tagged with
+/// the request id, and requests are answered in the order they finish.
+#[tracing::instrument(name = "session", skip_all, fields(client = %client.client_name))]
+pub async fn run_session(tls_stream: TlsStream<TcpStream>, storage: Arc<Storage>, settings: Arc<Settings>, protocol: NegotiatedProtocol, client: Arc<Authorization>){
+    let (reader, mut writer) = tokio::io::split(tls_stream);
+    let mut reader = LimitedReader::new(reader, settings.max_message_bytes);
+    let (outgoing, mut outgoing_rx) = mpsc::unbounded_channel::<Message>();
+
+    let session = Arc::new(Session{
+        storage,
+        settings,
+        protocol,
+        client,
+        outgoing,
+        requests: Mutex::new(HashMap::new()),
+        pending_templates: Mutex::new(HashMap::new()),
+        template_lock: tokio::sync::Mutex::new(()),
+        closed: AtomicBool::new(false),
+    });
+
+    // Once closed, the writer sends the messages still queued and stops
+    let (close_writer, mut writer_closed) = oneshot::channel::<()>();
+    let mut writer_task = tokio::spawn(async move{
+        loop{
+            let msg = tokio::select!{
+                msg = outgoing_rx.recv() => match msg{
+                    Some(msg) => msg,
+                    None => break
+                },
+                _ = &mut writer_closed => {
+                    while let Ok(msg) = outgoing_rx.try_recv(){
+                        if let Err(_) = vb_exchange::send_message(&mut writer, msg).await{
+                            break;
+                        }
+                    }
+                    break;
+                }
+            };
+            if let Err(_) = vb_exchange::send_message(&mut writer, msg).await{
+                warn!("Couldn't send message to server. Closing session.");
+                break;
+            }
+        }
+    }.in_current_span());
+
+    let session_cpy = session.clone();
+    let drained = Arc::new(Notify::new());
+    let drained_cpy = drained.clone();
+    let status_task = tokio::spawn(async move{
+        loop{
+            session_cpy.send_status_updates();
+            if session_cpy.storage.is_draining() && session_cpy.requests.lock().unwrap().is_empty(){
+                drained_cpy.notify_one();
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(500)).await;
+        }
+    });
+
+    loop{
+        let msg = tokio::select!{
+            res = limits::read_message(&mut reader) => match res{
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("{} Closing session.", e);
+                    break;
+                }
+            },
+            _ = drained.notified() => {
+                info!("Draining and all requests answered, closing session.");
+                break;
+            }
+        };
+
+        match msg{
+            Message::RenderingRequest(req) => {
+                session.register(req.request_id);
+                let session = session.clone();
+                let span = tracing::info_span!("rendering_request", request_id = %req.request_id);
+                tokio::spawn(async move{
+                    session.submit(req).await;
+                }.instrument(span));
+            },
+            Message::TemplateDataResult(template_data) => {
+                let waiting = session.pending_templates.lock().unwrap().remove(&template_data.template_version_id);
+                match waiting{
+                    Some(waiting) => {
+                        let _ = waiting.send(Message::TemplateDataResult(template_data));
+                    },
+                    None if session.protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => {
+                        // Template pushed ahead of the rendering requests
+                        if !session.client.permits(&session.settings, Action::UploadTemplate){
+                            let _ = session.outgoing.send(Message::CommunicationError(CommunicationError::Unauthorized));
+                            continue;
+                        }
+                        let session = session.clone();
+                        tokio::spawn(async move{
+                            session.save_pushed_template(template_data).await;
+                        }.in_current_span());
+                    },
+                    None => {
+                        warn!("Received template data that wasn't requested, ignoring it.");
+                        let _ = session.outgoing.send(Message::CommunicationError(CommunicationError::UnexpectedMessageType));
+                    }
+                }
+            },
+            Message::TemplateDeltaResult(delta) => {
+                let waiting = session.pending_templates.lock().unwrap().remove(&delta.template_version_id);
+                match waiting{
+                    Some(waiting) => {
+                        let _ = waiting.send(Message::TemplateDeltaResult(delta));
+                    },
+                    None => warn!("Received template delta that wasn't requested, ignoring it.")
+                }
+            },
+            Message::TemplatePrefetchRequest(req) if session.protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => {
+                if !session.client.permits(&session.settings, Action::UploadTemplate){
+                    let _ = session.outgoing.send(Message::CommunicationError(CommunicationError::Unauthorized));
+                    continue;
+                }
+                let session = session.clone();
+                tokio::spawn(async move{
+                    if let Err(e) = session.fetch_template(req.template_id, req.template_version_id).await{
+                        warn!("Couldn't prefetch template: {:?}", e);
+                    }
+                    session.send_cached_templates();
+                }.in_current_span());
+            },
+            Message::CachedTemplatesRequest if session.protocol.supports(protocol::FEATURE_TEMPLATE_PREFETCH) => session.send_cached_templates(),
+            Message::CapabilitiesRequest if session.protocol.supports(protocol::FEATURE_CAPABILITIES) => {
+                let _ = session.outgoing.send(Message::Capabilities(collect_capabilities(&session.storage, &session.settings)));
+            },
+            Message::Drain if session.protocol.supports(protocol::FEATURE_DRAIN) => {
+                if !session.client.permits(&session.settings, Action::Drain){
+                    let _ = session.outgoing.send(Message::CommunicationError(CommunicationError::Unauthorized));
+                    continue;
+                }
+                session.storage.request_drain();
+                let _ = session.outgoing.send(Message::Capabilities(collect_capabilities(&session.storage, &session.settings)));
+            },
+            Message::RetainedWorkspaceRequest(req) if session.protocol.supports(protocol::FEATURE_WORKSPACE_RETENTION) => {
+                let session = session.clone();
+                tokio::spawn(async move{
+                    let archive = retained_workspace_archive(session.settings.clone(), req.request_id, &session.client).await;
+                    let _ = session.outgoing.send(Message::RetainedWorkspace(RetainedWorkspace{ request_id: req.request_id, archive }));
+                }.in_current_span());
+            },
+            Message::CloseSession => break,
+            _ => {
+                warn!("Received unexpected Message type in session, ignoring it.");
+                let _ = session.outgoing.send(Message::CommunicationError(CommunicationError::UnexpectedMessageType));
+            }
+        }
+    }
+
+    status_task.abort();
+    let _ = close_writer.send(());
+    if let Err(_) = tokio::time::timeout(Duration::from_secs(10), &mut writer_task).await{
+        warn!("Couldn't send remaining messages to server in time.");
+        writer_task.abort();
+    }
+
+    // Wake up tasks still waiting for templates, they fail their requests
+    session.pending_templates.lock().unwrap().clear();
+
+    // Results of this session can't be delivered anymore, so its requests aren't rendered
+    session.closed.store(true, Ordering::SeqCst);
+    let request_ids: Vec<uuid::Uuid> = session.requests.lock().unwrap().drain().map(|(request_id, _)| request_id).collect();
+    for request_id in &request_ids{
+        session.storage.cancel(*request_id);
+    }
+    let mut status_storage = session.storage.request_status.write().unwrap();
+    for request_id in request_ids{
+        status_storage.remove(&request_id);
+    }
+}
+
+impl Session{
+    /// Adds a request to the session for status updates
+    ///
+    /// The status is inserted first, so the status updates never see a request without one.
+    fn register(&self, request_id: uuid::Uuid){
+        self.storage.request_status.write().unwrap().insert(request_id, RenderingStatus::SendToRenderingServer);
+        self.requests.lock().unwrap().insert(request_id, None);
+    }
+
+    /// Fetches the template if necessary, saves the uploads and queues the rendering request
+    async fn submit(&self, mut rendering_request: RenderingRequest){
+        let request_id = rendering_request.request_id;
+        let status_storage = &self.storage.request_status;
+
+        info!(template_id = %rendering_request.template_id, export_formats = ?rendering_request.export_formats, "Received rendering request.");
+
+        let admission = match admit_request(&self.storage, &self.settings, &self.client, &rendering_request){
+            Ok(admission) => admission,
+            Err(reason) => {
+                status_storage.write().unwrap().insert(request_id, RenderingStatus::Rejected(reason));
+                return;
+            }
+        };
+
+        let cached = template_cache::is_current_version(&self.storage, &self.client.template_namespace, rendering_request.template_id, rendering_request.template_version_id);
+        self.storage.metrics.template_cache(cached);
+        if !cached{
+            if let Some(status) = status_storage.write().unwrap().get_mut(&request_id){
+                *status = RenderingStatus::RequestingTemplate
+            }
+
+            if let Err(e) = self.fetch_template(rendering_request.template_id, rendering_request.template_version_id).await{
+                error!("Couldn't get template: {:?}", e);
+                status_storage.write().unwrap().insert(request_id, RenderingStatus::Failed(e));
+                return;
+            }
+        }
+
+        if let Err(e) = save_uploads(&self.storage, &self.settings, &self.client, &mut rendering_request).await{
+            error!("Couldn't save uploads: {:?}", e);
+            status_storage.write().unwrap().insert(request_id, RenderingStatus::Failed(e));
+            return;
+        }
+
+        self.storage.enqueue(rendering_request, self.client.clone(), admission);
+        // The session may have ended while the template or uploads were transferred
+        if self.closed.load(Ordering::SeqCst){
+            self.storage.cancel(request_id);
+        }
+    }
+
+    /// Requests a template version from the main server, as delta if possible, and saves it to the template storage
+    async fn fetch_template(&self, template_id: uuid::Uuid, template_version_id: uuid::Uuid) -> Result<(), RenderingError>{
+        let _lock = self.template_lock.lock().await;
+        let namespace = &self.client.template_namespace;
+        if template_cache::is_current_version(&self.storage, namespace, template_id, template_version_id){
+            return Ok(());
+        }
+
+        let cached_versions = cached_versions_to_advertise(&self.storage, &self.settings, &self.protocol, namespace, template_id);
+        let request = if cached_versions.is_empty(){
+            Message::TemplateDataRequest(TemplateDataRequest{ template_id, template_version_id })
+        }else{
+            Message::TemplateDeltaRequest(TemplateDeltaRequest{ template_id, template_version_id, cached_versions })
+        };
+
+        let export_formats = match self.request_template_data(template_version_id, request).await?{
+            Message::TemplateDataResult(template_data) => self.write_requested_template(template_id, template_data).await?,
+            Message::TemplateDeltaResult(delta) => {
+                if delta.template_id != template_id{
+                    return Err(RenderingError::Other("Received unexpected template delta.".to_string()));
+                }
+                match template_cache::assemble_from_delta(&self.settings, namespace, delta).await{
+                    Ok(export_formats) => export_formats,
+                    Err(e) => {
+                        warn!("Couldn't assemble template from delta, requesting full template: {}", e);
+                        match self.request_template_data(template_version_id, Message::TemplateDataRequest(TemplateDataRequest{ template_id, template_version_id })).await?{
+                            Message::TemplateDataResult(template_data) => self.write_requested_template(template_id, template_data).await?,
+                            _ => return Err(RenderingError::Other("Received unexpected Message type.".to_string()))
+                        }
+                    }
+                }
+            },
+            _ => return Err(RenderingError::Other("Received unexpected Message type.".to_string()))
+        };
+
+        register_template(&self.storage, &self.settings, namespace, template_id, template_version_id, export_formats).await
+    }
+
+    /// Sends a template request to the main server and waits for the answer routed back by the reader loop
+    async fn request_template_data(&self, template_version_id: uuid::Uuid, request: Message) -> Result<Message, RenderingError>{
+        let (sender, receiver) = oneshot::channel();
+        self.pending_templates.lock().unwrap().insert(template_version_id, sender);
+
+        if let Err(_) = self.outgoing.send(request){
+            self.pending_templates.lock().unwrap().remove(&template_version_id);
+            return Err(RenderingError::Other("Session closed.".to_string()));
+        }
+
+        receiver.await.map_err(|_| RenderingError::Other("Session closed before the template was received.".to_string()))
+    }
+
+    async fn write_requested_template(&self, template_id: uuid::Uuid, template_data: TemplateDataResult) -> Result<HashMap<String, ExportFormat>, RenderingError>{
+        if template_data.template_id != template_id{
+            return Err(RenderingError::Other("Received unexpected template data.".to_string()));
+        }
+        write_template_data(&self.storage, &self.settings, &self.client.template_namespace, template_data).await
+    }
+
+    async fn save_pushed_template(&self, template_data: TemplateDataResult){
+        let _lock = self.template_lock.lock().await;
+        let (template_id, template_version_id) = (template_data.template_id, template_data.template_version_id);
+
+        let namespace = &self.client.template_namespace;
+        let res = match write_template_data(&self.storage, &self.settings, namespace, template_data).await{
+            Ok(export_formats) => register_template(&self.storage, &self.settings, namespace, template_id, template_version_id, export_formats).await,
+            Err(e) => Err(e)
+        };
+        if let Err(e) = res{
+            error!("Couldn't save pushed template: {:?}", e);
+        }
+        self.send_cached_templates();
+    }
+
+    fn send_cached_templates(&self){
+        let templates = template_cache::list_cached_templates(&self.storage, &self.client.template_namespace);
+        let _ = self.outgoing.send(Message::CachedTemplatesResult(CachedTemplatesResult{ templates }));
+    }
+
+    /// Sends the status of every request whose status changed since the last update
+    ///
+    /// Finished & failed requests are removed from the session and the status storage after sending.
+    fn send_status_updates(&self){
+        let mut requests = self.requests.lock().unwrap();
+        let mut done = Vec::new();
+
+        for (request_id, last_status) in requests.iter_mut(){
+            let status = match self.storage.request_status.read().unwrap().get(request_id){
+                Some(res) => res.clone(),
+                None => RenderingStatus::Failed(RenderingError::Other("Not Found".to_string()))
+            };
+
+            let kind = std::mem::discriminant(&status);
+            if *last_status == Some(kind){
+                continue;
+            }
+            *last_status = Some(kind);
+
+            if matches!(status, RenderingStatus::Finished(_) | RenderingStatus::Failed(_) | RenderingStatus::Rejected(_)){
+                done.push(*request_id);
+            }
+            let _ = self.outgoing.send(Message::TaggedRenderingStatus(TaggedRenderingStatus{ request_id: *request_id, status: self.protocol.downgrade_status(status) }));
+        }
+
+        for request_id in done{
+            requests.remove(&request_id);
+            self.storage.request_status.write().unwrap().remove(&request_id);
+        }
+    }
+}
@@ -1,6 +1,14 @@
 use std::env;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use config::{Config, ConfigError, Environment, File};
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
+use crate::authorization::{is_valid_namespace, ClientPolicy};
+use crate::logging::LogFormat;
+use crate::self_check::SelfCheckMode;
 
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
@@ -8,7 +16,7 @@ pub struct Settings {
     /// hostname of this rendering server
     pub bind_to_host: String,
     /// port to listen on
-    pub port: usize,
+    pub port: u16,
     /// Path to the CA certificate
     pub ca_cert_path: String,
     /// Path to the client certificate
@@ -17,23 +25,343 @@ pub struct Settings {
     pub client_key_path: String,
     /// Path to the revocation list
     pub revocation_list_path: String,
+    /// Seconds between checks if the certificates, key or revocation list changed, 0 to only reload on SIGHUP
+    #[serde(deserialize_with = "seconds")]
+    pub tls_reload_interval: Duration,
     /// Path to the folder where templates data are stored temporarily. Gets cleared on start
     pub temp_template_path: String,
+    /// Path to the folder where the working directories of the export steps are created. Gets cleared on start
+    pub job_work_path: String,
+    /// Path to the folder where uploaded project files are stored until the rendering finished. Gets cleared on start
+    pub upload_path: String,
+    /// Path to the vivliostyle rendering environment (node, vivliostyle & chromium)
+    pub vivliostyle_env_path: String,
+    /// Path to the pandoc rendering environment
+    pub pandoc_env_path: String,
     /// Max concurrent rendering threads
-    pub max_rendering_threads: u64,
+    pub max_rendering_threads: NonZeroU64,
+    /// Seconds between two sweeps for orphaned job & upload directories
+    #[serde(deserialize_with = "seconds")]
+    pub janitor_interval: Duration,
+    /// Seconds after which job & upload directories count as orphaned
+    #[serde(deserialize_with = "seconds")]
+    pub orphan_max_age: Duration,
+    /// Keep the workspaces of all failed rendering requests, main servers may also request it per rendering request
+    pub retain_failed_workspaces: bool,
+    /// Path to keep failed workspaces in
+    pub retained_workspace_path: String,
+    /// Seconds to keep failed workspaces
+    #[serde(deserialize_with = "seconds")]
+    pub failed_workspace_retention: Duration,
+    /// Max queued rendering requests, further requests are rejected as busy
+    pub max_queue_depth: u64,
+    /// Max size of a single message from a main server, checked while reading it
+    pub max_message_bytes: u64,
+    /// Max total size of the uploaded project files of a rendering request
+    pub max_upload_bytes: u64,
+    /// Max total size of a template version
+    pub max_template_bytes: u64,
+    /// Max size of a single uploaded or template file
+    pub max_file_bytes: u64,
+    /// Max number of files & directories in uploads or a template
+    pub max_files_per_tree: u64,
+    /// Max directory nesting in uploads or a template
+    pub max_tree_depth: u64,
+    /// Advertise cached template versions to the main server, so it may only send the changed files of a new version
+    pub template_delta_transfer: bool,
+    /// Number of versions per template kept in the temp template dir as base for delta transfers
+    pub max_cached_template_versions: usize,
+    /// Refuse to start or only mark engines as unavailable if the startup self-check fails
+    pub self_check_mode: SelfCheckMode,
+    /// Address (host:port) of a main server to connect to, for rendering servers without inbound reachability
+    pub main_server_address: Option<String>,
+    /// Name in the certificate of the main server, defaults to the host of main_server_address
+    pub main_server_name: Option<String>,
+    /// Max seconds to wait between two connection attempts to the main server
+    #[serde(deserialize_with = "seconds")]
+    pub reverse_connect_max_backoff: Duration,
+    /// Max seconds to wait for queued & running rendering requests on shutdown
+    #[serde(deserialize_with = "seconds")]
+    pub shutdown_deadline: Duration,
+    /// Reject new rendering requests while draining instead of queueing them until the shutdown deadline
+    pub reject_jobs_while_draining: bool,
+    /// Policies of the main servers allowed to connect, matched by certificate common name or DNS SAN
+    #[serde(default)]
+    pub clients: Vec<ClientPolicy>,
+    /// Allow main servers signed by the CA without a matching policy, using the default template namespace
+    pub allow_unlisted_clients: bool,
+    /// File denied connections & actions are appended to
+    pub audit_log_path: Option<String>,
+    /// Minimum level of log messages or a filter like "info,Verfassungsbooks_Rendering_Server=debug", overridden by RUST_LOG
+    pub log_level: String,
+    /// Log as human readable text or as one JSON object per line
+    pub log_format: LogFormat,
+    /// Address to serve Prometheus metrics on at /metrics, disabled if not set
+    pub metrics_address: Option<SocketAddr>,
+    /// Loopback address to serve the admin API on, disabled if not set
+    pub admin_address: Option<SocketAddr>,
+    /// Bearer token required for the actions of the admin API, actions are disabled if not set
+    pub admin_token: Option<String>,
 }
 
 impl Settings{
-    pub fn new() -> Result<Self, ConfigError>{
-        let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
-
-        let s = Config::builder().add_source(File::with_name("config/default"))
-            .add_source( File::with_name(&format!("config/{}", run_mode))
-                             .required(false),)
-            .add_source(File::with_name("config/local").required(false))
-            .add_source(Environment::with_prefix("app"))
+    /// Reads config/default, config/<RUN_MODE> & config/local relative to the working directory.
+    /// A given config file replaces config/<RUN_MODE> & config/local, so it only needs the settings differing from config/default.
+    /// `APP_*` environment variables override both
+    pub fn new(config: Option<&Path>) -> Result<Self, ConfigError>{
+        let builder = match config{
+            // The given file may be complete on its own, e.g. if started outside the installation directory
+            Some(config) => Config::builder().add_source(File::with_name("config/default").required(false))
+                .add_source(File::from(config)),
+            None => {
+                let run_mode = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+
+                Config::builder().add_source(File::with_name("config/default"))
+                    .add_source( File::with_name(&format!("config/{}", run_mode))
+                                     .required(false),)
+                    .add_source(File::with_name("config/local").required(false))
+            }
+        };
+
+        let s = builder.add_source(Environment::with_prefix("app"))
             .build()?;
 
         s.try_deserialize()
     }
-}
\ No newline at end of file
+
+    /// Checks the settings for problems serde can't catch, like missing files, unusable limits or clashing directories
+    ///
+    /// Returns all problems at once, so they can be fixed in one go
+    pub fn validate(&self) -> Result<(), Vec<String>>{
+        let mut problems = Vec::new();
+
+        if let Err(e) = self.listen_address(){
+            problems.push(e);
+        }
+        if let Some(address) = &self.main_server_address{
+            if !address.rsplit_once(':').is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok()){
+                problems.push(format!("main_server_address ({}) must be host:port.", address));
+            }
+        }
+
+        for (name, path) in [("ca_cert_path", &self.ca_cert_path), ("client_cert_path", &self.client_cert_path), ("client_key_path", &self.client_key_path), ("revocation_list_path", &self.revocation_list_path)]{
+            if let Err(e) = fs::File::open(path){
+                problems.push(format!("{} ({}) can't be read: {}.", name, path, e));
+            }
+        }
+
+        for (name, path) in [("vivliostyle_env_path", &self.vivliostyle_env_path), ("pandoc_env_path", &self.pandoc_env_path)]{
+            if !Path::new(path).is_dir(){
+                problems.push(format!("{} ({}) is not a directory. Check your settings or run rendering-envs/setup.sh.", name, path));
+            }
+        }
+
+        self.check_working_dirs(&mut problems);
+
+        if self.janitor_interval.is_zero(){
+            problems.push("janitor_interval must be greater than 0.".to_string());
+        }
+        if self.reverse_connect_max_backoff.is_zero(){
+            problems.push("reverse_connect_max_backoff must be greater than 0.".to_string());
+        }
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log_level){
+            problems.push(format!("log_level ({}) is not a valid filter: {}.", self.log_level, e));
+        }
+        if self.max_cached_template_versions == 0{
+            problems.push("max_cached_template_versions must be greater than 0.".to_string());
+        }
+
+        if let Some(address) = self.admin_address{
+            if !address.ip().is_loopback(){
+                problems.push(format!("admin_address ({}) must be a loopback address, reading the admin API needs no authentication.", address));
+            }
+        }
+        if self.admin_token.as_ref().is_some_and(|token| token.len() < 16){
+            problems.push("admin_token must be at least 16 characters long.".to_string());
+        }
+
+        for policy in &self.clients{
+            let namespace = policy.template_namespace.as_ref().unwrap_or(&policy.name);
+            if !is_valid_namespace(namespace){
+                problems.push(format!("Template namespace {} of client {} may only contain letters, digits, - and _.", namespace, policy.name));
+            }
+        }
+
+        if problems.is_empty(){
+            Ok(())
+        }else{
+            Err(problems)
+        }
+    }
+
+    /// Checks the working directories & tree limits, the part of [Settings::validate] needed by commands not listening for main servers
+    pub fn validate_working_dirs(&self) -> Result<(), Vec<String>>{
+        let mut problems = Vec::new();
+        self.check_working_dirs(&mut problems);
+        if problems.is_empty(){
+            Ok(())
+        }else{
+            Err(problems)
+        }
+    }
+
+    fn check_working_dirs(&self, problems: &mut Vec<String>){
+        // The working directories are cleared independently, so they must not overlap
+        let working_dirs = [("temp_template_path", &self.temp_template_path), ("job_work_path", &self.job_work_path), ("upload_path", &self.upload_path), ("retained_workspace_path", &self.retained_workspace_path)];
+        // Clearing a working directory must never delete the installation, the rendering environments, certificates or logs
+        let mut protected = vec![("the working directory", ".".to_string()), ("the config directory", "config".to_string()), ("vivliostyle_env_path", self.vivliostyle_env_path.clone()), ("pandoc_env_path", self.pandoc_env_path.clone()),
+                                 ("ca_cert_path", self.ca_cert_path.clone()), ("client_cert_path", self.client_cert_path.clone()), ("client_key_path", self.client_key_path.clone()), ("revocation_list_path", self.revocation_list_path.clone())];
+        if let Some(audit_log_path) = &self.audit_log_path{
+            protected.push(("audit_log_path", audit_log_path.clone()));
+        }
+        for (i, (name, path)) in working_dirs.iter().enumerate(){
+            if path.is_empty(){
+                problems.push(format!("{} must not be empty.", name));
+                continue;
+            }
+            let dir = absolute(path);
+            for (other_name, other_path) in &working_dirs[i+1..]{
+                let other = absolute(other_path);
+                if !other_path.is_empty() && (dir.starts_with(&other) || other.starts_with(&dir)){
+                    problems.push(format!("{} ({}) and {} ({}) must be separate directories.", name, path, other_name, other_path));
+                }
+            }
+            for (protected_name, protected_path) in &protected{
+                if !protected_path.is_empty() && absolute(protected_path).starts_with(&dir){
+                    problems.push(format!("{} ({}) contains {} ({}), which would be deleted on startup.", name, path, protected_name, protected_path));
+                }
+            }
+        }
+
+        for (name, value) in [("max_message_bytes", self.max_message_bytes), ("max_upload_bytes", self.max_upload_bytes), ("max_template_bytes", self.max_template_bytes), ("max_file_bytes", self.max_file_bytes), ("max_files_per_tree", self.max_files_per_tree), ("max_tree_depth", self.max_tree_depth)]{
+            if value == 0{
+                problems.push(format!("{} must be greater than 0.", name));
+            }
+        }
+        if self.max_upload_bytes > self.max_message_bytes || self.max_template_bytes > self.max_message_bytes{
+            problems.push("max_upload_bytes and max_template_bytes must not exceed max_message_bytes, they are sent in a single message.".to_string());
+        }
+    }
+
+    /// Resolves bind_to_host & port to the address to listen on
+    pub fn listen_address(&self) -> Result<SocketAddr, String>{
+        // IPv6 addresses are written with brackets in the config
+        let host = self.bind_to_host.trim_start_matches('[').trim_end_matches(']');
+        match (host, self.port).to_socket_addrs(){
+            Ok(mut addresses) => addresses.next().ok_or_else(|| format!("bind_to_host ({}) doesn't resolve to an address.", self.bind_to_host)),
+            Err(e) => Err(format!("bind_to_host ({}) is not a valid address: {}.", self.bind_to_host, e))
+        }
+    }
+}
+
+/// Joins the problems found by [Settings::validate_working_dirs] into one error message
+pub fn describe_problems(problems: &[String]) -> String{
+    problems.iter().map(|problem| format!("Invalid config: {}", problem)).collect::<Vec<_>>().join("\n")
+}
+
+/// Absolute form of a path for comparisons, with symlinks resolved if it exists
+fn absolute(path: &str) -> PathBuf{
+    fs::canonicalize(path).or_else(|_| std::path::absolute(path)).unwrap_or_else(|_| PathBuf::from(path))
+}
+
+/// Reads a number of seconds
+fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error>{
+    u64::deserialize(deserializer).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn problems(settings: &Settings) -> Vec<String>{
+        settings.validate().err().unwrap_or_default()
+    }
+
+    fn has_problem(settings: &Settings, needle: &str) -> bool{
+        problems(settings).iter().any(|problem| problem.contains(needle))
+    }
+
+    #[test]
+    fn default_working_dirs_are_separate(){
+        let settings = Settings::new(None).unwrap();
+        assert!(!has_problem(&settings, "must be separate directories"));
+        assert!(!has_problem(&settings, "must not be empty"));
+    }
+
+    #[test]
+    fn rejects_overlapping_working_dirs(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.upload_path = format!("{}/uploads", settings.job_work_path);
+        assert!(has_problem(&settings, "job_work_path"));
+        assert!(has_problem(&settings, "must be separate directories"));
+
+        let mut settings = Settings::new(None).unwrap();
+        settings.retained_workspace_path = settings.temp_template_path.clone();
+        assert!(has_problem(&settings, "must be separate directories"));
+    }
+
+    #[test]
+    fn rejects_overlap_spelled_differently(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.upload_path = std::path::absolute(&settings.job_work_path).unwrap().join("uploads").to_string_lossy().to_string();
+        assert!(has_problem(&settings, "must be separate directories"));
+    }
+
+    #[test]
+    fn rejects_working_dirs_containing_protected_paths(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.job_work_path = ".".to_string();
+        assert!(has_problem(&settings, "contains the working directory (.)"));
+
+        let mut settings = Settings::new(None).unwrap();
+        settings.upload_path = "rendering-envs".to_string();
+        assert!(has_problem(&settings, "contains vivliostyle_env_path"));
+        assert!(has_problem(&settings, "contains pandoc_env_path"));
+
+        let mut settings = Settings::new(None).unwrap();
+        settings.ca_cert_path = format!("{}/root.crt", settings.retained_workspace_path);
+        assert!(has_problem(&settings, "contains ca_cert_path"));
+
+        let mut settings = Settings::new(None).unwrap();
+        settings.audit_log_path = Some(format!("{}/audit.log", settings.temp_template_path));
+        assert!(has_problem(&settings, "contains audit_log_path"));
+    }
+
+    #[test]
+    fn rejects_empty_working_dirs(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.job_work_path = String::new();
+        assert!(has_problem(&settings, "job_work_path must not be empty."));
+    }
+
+    #[test]
+    fn rejects_zero_limits(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.max_file_bytes = 0;
+        settings.max_tree_depth = 0;
+        settings.janitor_interval = Duration::ZERO;
+        assert!(has_problem(&settings, "max_file_bytes must be greater than 0."));
+        assert!(has_problem(&settings, "max_tree_depth must be greater than 0."));
+        assert!(has_problem(&settings, "janitor_interval must be greater than 0."));
+    }
+
+    #[test]
+    fn working_dirs_are_validated_without_certificates(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.ca_cert_path = "missing/ca.pem".to_string();
+        assert!(settings.validate_working_dirs().is_ok());
+
+        settings.job_work_path = settings.upload_path.clone();
+        settings.max_files_per_tree = 0;
+        let problems = settings.validate_working_dirs().unwrap_err();
+        assert!(problems.iter().any(|problem| problem.starts_with("job_work_path")));
+        assert!(problems.contains(&"max_files_per_tree must be greater than 0.".to_string()));
+    }
+
+    #[test]
+    fn uploads_must_fit_into_a_message(){
+        let mut settings = Settings::new(None).unwrap();
+        settings.max_upload_bytes = settings.max_message_bytes + 1;
+        assert!(has_problem(&settings, "must not exceed max_message_bytes"));
+    }
+}
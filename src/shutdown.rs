@@ -0,0 +1,52 @@
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::Instant;
+use tracing::{error, info, warn};
+use crate::settings::Settings;
+use crate::storage::Storage;
+
+/// Waits for SIGTERM, SIGINT or a drain request from a main server, then switches to drain mode
+pub async fn wait_for_shutdown(storage: &Storage){
+    // Registered right after startup, so failing exits like the other startup failures
+    let mut sigterm = match signal(SignalKind::terminate()){
+        Ok(sigterm) => sigterm,
+        Err(e) => {
+            error!("Couldn't register SIGTERM handler: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    tokio::select!{
+        _ = sigterm.recv() => info!("Received SIGTERM, draining."),
+        _ = tokio::signal::ctrl_c() => info!("Received SIGINT, draining."),
+        _ = storage.drain_requested.notified() => info!("Drain requested by main server."),
+    }
+
+    storage.draining.store(true, Ordering::Relaxed);
+}
+
+/// Waits until all rendering requests finished & their results were sent, or shutdown_deadline passed
+///
+/// Requests still fetching their template or saving uploads have a status but aren't queued yet,
+/// finished requests keep theirs until the connection sent it to the main server.
+pub async fn drain(storage: &Storage, settings: &Settings){
+    let deadline = Instant::now() + settings.shutdown_deadline;
+
+    loop{
+        let queued = storage.request_queue.read().unwrap().len();
+        let running = storage.running_jobs.load(Ordering::Relaxed);
+        let undelivered = storage.request_status.read().unwrap().len();
+        let connections = storage.open_connections.load(Ordering::Relaxed);
+        if queued == 0 && running == 0 && undelivered == 0 && connections == 0{
+            info!("All rendering requests finished and delivered.");
+            return;
+        }
+        if Instant::now() >= deadline{
+            warn!("Shutdown deadline passed, aborting {} queued and {} running rendering requests, {} results undelivered, {} connections open.", queued, running, undelivered, connections);
+            return;
+        }
+
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
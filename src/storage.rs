@@ -1,36 +1,257 @@
 use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions, TryLockError};
 use std::io;
 use std::path::Path;
-use std::sync::{Arc, RwLock};
-use vb_exchange::{RenderingRequest, RenderingStatus};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+use tokio::sync::Notify;
+use vb_exchange::{FilesOnMemoryOrHarddrive, RenderingError, RenderingRequest, RenderingStatus, TemplateVersionManifest};
 use vb_exchange::export_formats::ExportFormat;
+use crate::admission::{Admission, JobDurationEstimate};
+use crate::authorization::Authorization;
+use crate::metrics::Metrics;
+use crate::quotas::{JobSlot, QuotaTracker};
+use crate::self_check::EnvironmentReport;
 use crate::settings::Settings;
+use crate::workspace::Workspace;
 
 pub struct Storage{
-    pub request_queue: Arc<RwLock<VecDeque<RenderingRequest>>>,
+    /// Queued rendering requests, ordered by priority of their clients
+    pub request_queue: Arc<RwLock<VecDeque<QueuedRequest>>>,
+    /// Admitted rendering requests not queued yet, still fetching their template or saving uploads
+    pub pending_requests: Arc<AtomicU64>,
     pub request_status: Arc<RwLock<HashMap<uuid::Uuid, RenderingStatus>>>,
-    /// Contains a HashMap with template_id as key, template_version_id as value.
-    pub template_storage: Arc<RwLock<HashMap<uuid::Uuid, TemplateStorageEntry>>>
+    /// Contains a HashMap with template namespace & template_id as key
+    pub template_storage: Arc<RwLock<HashMap<(String, uuid::Uuid), TemplateStorageEntry>>>,
+    /// Result of the startup self-check of the rendering environments
+    pub environment: Arc<RwLock<EnvironmentReport>>,
+    /// Number of rendering requests currently rendered by the rendering worker
+    pub running_jobs: Arc<AtomicU64>,
+    /// Rendering requests currently rendered, by request id
+    pub running_requests: Arc<RwLock<HashMap<uuid::Uuid, Arc<RunningRequest>>>>,
+    /// Connections to main servers currently handling messages, including sessions
+    pub open_connections: Arc<AtomicU64>,
+    /// Set while shutting down, running & queued requests are finished but no new connections are accepted
+    pub draining: Arc<AtomicBool>,
+    /// Notified if a main server requests a drain
+    pub drain_requested: Arc<Notify>,
+    /// Usage of every client for the quotas of their policies
+    pub quotas: Arc<QuotaTracker>,
+    /// Average duration of finished rendering requests
+    pub job_durations: Arc<JobDurationEstimate>,
+    /// Counters & histograms for the metrics endpoint
+    pub metrics: Arc<Metrics>,
+}
+
+/// Rendering request waiting for the rendering worker, with the main server that sent it
+pub struct QueuedRequest{
+    pub request: Arc<RenderingRequest>,
+    pub client: Arc<Authorization>,
+    /// Concurrent job of the client, released when the request is dropped after rendering
+    pub slot: JobSlot,
+}
+
+/// Progress of a rendering request rendered by the rendering worker
+pub struct RunningRequest{
+    pub client: String,
+    pub template_namespace: String,
+    pub template_id: uuid::Uuid,
+    pub template_version_id: uuid::Uuid,
+    pub export_formats: Vec<String>,
+    pub started: Instant,
+    /// Name of the export step currently rendered, by export format slug
+    pub current_steps: Mutex<HashMap<String, String>>,
+    /// Set on cancellation or once an export format failed, export formats fail before their next export step
+    pub cancelled: AtomicBool,
+}
+
+/// Running rendering request, decrements running_jobs & removes it from running_requests when dropped
+pub struct RunningJob{
+    request_id: uuid::Uuid,
+    running_jobs: Arc<AtomicU64>,
+    running_requests: Arc<RwLock<HashMap<uuid::Uuid, Arc<RunningRequest>>>>,
+}
+
+impl Drop for RunningJob{
+    fn drop(&mut self){
+        self.running_requests.write().unwrap().remove(&self.request_id);
+        self.running_jobs.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Open connection to a main server, decrements open_connections when dropped
+pub struct ConnectionGuard{
+    open_connections: Arc<AtomicU64>,
+}
+
+impl Drop for ConnectionGuard{
+    fn drop(&mut self){
+        self.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 pub struct TemplateStorageEntry{
     pub version_id: uuid::Uuid,
-    pub export_formats: HashMap<String, ExportFormat>
+    /// Versions of this template still saved in the temp template dir, oldest first.
+    /// Includes the current version.
+    pub cached_versions: Vec<CachedVersion>,
+}
+
+impl TemplateStorageEntry{
+    /// Export formats of a cached version of the template
+    pub fn export_formats(&self, template_version_id: uuid::Uuid) -> Option<&HashMap<String, ExportFormat>>{
+        self.cached_versions.iter().find(|version| version.manifest.template_version_id == template_version_id).map(|version| &version.export_formats)
+    }
+}
+
+/// Template version saved in the temp template dir
+pub struct CachedVersion{
+    /// File hashes of the version, offered to the main server as base for deltas
+    pub manifest: TemplateVersionManifest,
+    pub export_formats: HashMap<String, ExportFormat>,
 }
 
 impl Storage{
     pub fn new() -> Storage{
         Storage{
             request_queue: Arc::new(Default::default()),
+            pending_requests: Arc::new(AtomicU64::new(0)),
             request_status: Arc::new(Default::default()),
             template_storage: Arc::new(Default::default()),
+            environment: Arc::new(Default::default()),
+            running_jobs: Arc::new(AtomicU64::new(0)),
+            running_requests: Arc::new(Default::default()),
+            open_connections: Arc::new(AtomicU64::new(0)),
+            draining: Arc::new(AtomicBool::new(false)),
+            drain_requested: Arc::new(Notify::new()),
+            quotas: Arc::new(QuotaTracker::default()),
+            job_durations: Arc::new(JobDurationEstimate::default()),
+            metrics: Arc::new(Metrics::default()),
+        }
+    }
+
+    pub fn is_draining(&self) -> bool{
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Queues a rendering request behind all requests with the same or a higher priority, releasing its place reserved in the backlog
+    pub fn enqueue(&self, request: RenderingRequest, client: Arc<Authorization>, admission: Admission){
+        let mut queue = self.request_queue.write().unwrap();
+        let position = queue.iter().position(|queued| queued.client.priority < client.priority).unwrap_or(queue.len());
+        queue.insert(position, QueuedRequest{ request: Arc::new(request), client, slot: admission.slot });
+        drop(admission.reservation);
+    }
+
+    /// Counts a rendering request as running until the returned guard is dropped
+    pub fn start_job(&self, queued: &QueuedRequest) -> RunningJob{
+        let request = &queued.request;
+        self.running_requests.write().unwrap().insert(request.request_id, Arc::new(RunningRequest{
+            client: queued.client.client_name.clone(),
+            template_namespace: queued.client.template_namespace.clone(),
+            template_id: request.template_id,
+            template_version_id: request.template_version_id,
+            export_formats: request.export_formats.clone(),
+            started: Instant::now(),
+            current_steps: Mutex::new(HashMap::new()),
+            cancelled: AtomicBool::new(false),
+        }));
+        self.running_jobs.fetch_add(1, Ordering::Relaxed);
+
+        RunningJob{
+            request_id: request.request_id,
+            running_jobs: self.running_jobs.clone(),
+            running_requests: self.running_requests.clone(),
         }
     }
+
+    /// Counts a connection as open until the returned guard is dropped, so draining waits for its last messages
+    pub fn open_connection(&self) -> ConnectionGuard{
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard{ open_connections: self.open_connections.clone() }
+    }
+
+    /// Removes a queued rendering request or stops a running one before its next export step
+    ///
+    /// Returns false if the request is neither queued nor running.
+    pub fn cancel(&self, request_id: uuid::Uuid) -> bool{
+        if let Some(running) = self.running_requests.read().unwrap().get(&request_id){
+            running.cancelled.store(true, Ordering::Relaxed);
+            return true;
+        }
+
+        let queued = {
+            let mut queue = self.request_queue.write().unwrap();
+            match queue.iter().position(|queued| queued.request.request_id == request_id){
+                Some(position) => queue.remove(position),
+                None => None
+            }
+        };
+        match queued{
+            Some(queued) => {
+                if let FilesOnMemoryOrHarddrive::Harddrive(path) = &queued.request.project_uploaded_files{
+                    drop(Workspace::adopt(path.clone()));
+                }
+                if let Some(status) = self.request_status.write().unwrap().get_mut(&request_id){
+                    *status = RenderingStatus::Failed(RenderingError::Other("Cancelled by operator.".to_string()));
+                }
+                true
+            },
+            None => false
+        }
+    }
+
+    /// Switches to drain mode & shuts down once all requests are finished
+    pub fn request_drain(&self){
+        self.draining.store(true, Ordering::Relaxed);
+        self.drain_requested.notify_one();
+    }
+}
+
+/// Lock on the working directories, held by a running server & released when dropped or the process exits
+pub struct WorkingDirLock{
+    _file: File,
+}
+
+/// Locks the working directories against other servers & cache commands, using a lock file next to the temp template dir
+pub fn lock_working_dirs(settings: &Settings) -> io::Result<WorkingDirLock>{
+    let path = Path::new(&settings.temp_template_path).with_extension("lock");
+    if let Some(parent) = path.parent(){
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+    match file.try_lock(){
+        Ok(()) => Ok(WorkingDirLock{ _file: file }),
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(io::ErrorKind::WouldBlock, format!("{} is used by a running server", settings.temp_template_path))),
+        Err(TryLockError::Error(e)) => Err(e)
+    }
+}
+
+/// Creates the template, job & upload directories if they don't exist and removes all leftovers from previous runs
+pub fn prepare_working_dirs(settings: &Settings) -> io::Result<()>{
+    for dir in [&settings.temp_template_path, &settings.job_work_path, &settings.upload_path]{
+        let path = Path::new(dir);
+        if path.exists(){
+            clear_dir(path)?;
+        }else{
+            std::fs::create_dir_all(path)?;
+        }
+    }
+    // Retained workspaces survive restarts until they expire
+    std::fs::create_dir_all(&settings.retained_workspace_path)?;
+
+    Ok(())
+}
+
+/// Removes the working directories & uploads of all rendering requests
+pub fn clear_job_dirs(settings: &Settings) -> io::Result<()>{
+    clear_dir(Path::new(&settings.job_work_path))?;
+    clear_dir(Path::new(&settings.upload_path))
 }
 
-/// Removes all files from temp template dir
-pub fn clear_template_dir(settings: &Settings) -> io::Result<()>{
-    let entries = std::fs::read_dir(Path::new(&settings.temp_template_path.clone()))?;
+/// Removes all files & directories inside the given directory, keeping the directory itself
+fn clear_dir(path: &Path) -> io::Result<()>{
+    let entries = std::fs::read_dir(path)?;
 
     for entry in entries{
         let entry = entry?.path();
@@ -42,4 +263,22 @@ pub fn clear_template_dir(settings: &Settings) -> io::Result<()>{
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn working_dirs_are_locked_until_the_lock_is_dropped(){
+        let dir = std::env::temp_dir().join(format!("vb-storage-test-{}", uuid::Uuid::new_v4()));
+        let mut settings = Settings::new(None).unwrap();
+        settings.temp_template_path = dir.join("templates").to_string_lossy().to_string();
+
+        let lock = lock_working_dirs(&settings).unwrap();
+        assert_eq!(lock_working_dirs(&settings).err().map(|e| e.kind()), Some(io::ErrorKind::WouldBlock));
+        drop(lock);
+        assert!(lock_working_dirs(&settings).is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
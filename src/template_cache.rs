@@ -0,0 +1,456 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+use vb_exchange::{CachedTemplate, TemplateDeltaResult, TemplateVersionManifest};
+use vb_exchange::export_formats::ExportFormat;
+use crate::limits;
+use crate::limits::TreeLimits;
+use crate::rendering::copy_dir_all;
+use crate::safe_path;
+use crate::settings::Settings;
+use crate::storage::{CachedVersion, Storage, TemplateStorageEntry};
+
+/// Directory of a template version inside the temp template dir
+pub fn template_dir(settings: &Settings, namespace: &str, template_version_id: uuid::Uuid) -> PathBuf{
+    PathBuf::from(&settings.temp_template_path).join(namespace).join(template_version_id.to_string())
+}
+
+/// Checks if the given version is the current version of the template in storage
+pub fn is_current_version(storage: &Storage, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid) -> bool{
+    match storage.template_storage.read().unwrap().get(&(namespace.to_string(), template_id)){
+        Some(entry) => entry.version_id == template_version_id,
+        None => false
+    }
+}
+
+/// Lists all templates of a namespace with their current and cached versions
+pub fn list_cached_templates(storage: &Storage, namespace: &str) -> Vec<CachedTemplate>{
+    storage.template_storage.read().unwrap().iter().filter(|((ns, _), _)| ns == namespace).map(|((_, template_id), entry)| CachedTemplate{
+        template_id: *template_id,
+        current_version_id: entry.version_id,
+        cached_version_ids: entry.cached_versions.iter().map(|version| version.manifest.template_version_id).collect(),
+    }).collect()
+}
+
+/// Calculates the sha256 hashes of all files inside a template directory
+///
+/// Keys are the file paths relative to the template directory, separated by `/`
+pub fn compute_manifest(template_dir: &Path) -> io::Result<HashMap<String, String>>{
+    let mut files = HashMap::new();
+    add_dir_to_manifest(template_dir, "", &mut files)?;
+    Ok(files)
+}
+
+fn add_dir_to_manifest(dir: &Path, prefix: &str, files: &mut HashMap<String, String>) -> io::Result<()>{
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        if entry.file_type()?.is_dir(){
+            add_dir_to_manifest(&entry.path(), &format!("{}/", name), files)?;
+        }else{
+            let content = fs::read(entry.path())?;
+            files.insert(name, format!("{:x}", Sha256::digest(&content)));
+        }
+    }
+    Ok(())
+}
+
+/// Builds a new template version from a cached base version and the files changed since then
+///
+/// The assembled directory is checked against the manifest sent by the main server and removed again on any mismatch.
+/// Returns the export formats of the new version.
+pub async fn assemble_from_delta(settings: &Settings, namespace: &str, delta: TemplateDeltaResult) -> Result<HashMap<String, ExportFormat>, String>{
+    let base_dir = template_dir(settings, namespace, delta.base_version_id);
+    let target_dir = template_dir(settings, namespace, delta.template_version_id);
+    let changes_dir = staging_dir(&target_dir);
+
+    if !base_dir.is_dir(){
+        return Err(format!("Base version {} isn't cached anymore.", delta.base_version_id));
+    }
+
+    let limits = TreeLimits::templates(settings);
+    if let Err(e) = safe_path::check_files(&delta.changed_files){
+        return Err(e.to_string());
+    }
+    if let Err(e) = limits::check_paths(&delta.changed_files, &limits){
+        return Err(e.to_string());
+    }
+    let removed_files = match delta.removed_files.iter().map(|file| safe_path::relative(file)).collect::<Result<Vec<_>, _>>(){
+        Ok(files) => files,
+        Err(e) => return Err(e.to_string()),
+    };
+
+    if let Err(e) = delta.changed_files.to_file(changes_dir.clone()).await{
+        let _ = tokio::fs::remove_dir_all(&changes_dir).await;
+        return Err(format!("Couldn't save changed template files: {}", e));
+    }
+
+    let manifest = delta.manifest;
+    let changes_dir_cpy = changes_dir.clone();
+    let assembled = tokio::task::spawn_blocking(move || assemble_version(&base_dir, &changes_dir_cpy, &target_dir, &removed_files, &limits, &manifest)).await;
+
+    let _ = tokio::fs::remove_dir_all(&changes_dir).await;
+
+    match assembled{
+        Ok(Ok(())) => Ok(delta.export_formats),
+        Ok(Err(e)) => Err(e),
+        Err(e) => Err(format!("Couldn't assemble template: {}", e))
+    }
+}
+
+/// Assembles a version next to target_dir, checks it against the manifest of the main server & moves it into place
+///
+/// On any mismatch the assembled version is removed again, an existing complete version in target_dir is kept.
+fn assemble_version(base_dir: &Path, changes_dir: &Path, target_dir: &Path, removed_files: &[PathBuf], limits: &TreeLimits, manifest: &HashMap<String, String>) -> Result<(), String>{
+    let assembly_dir = staging_dir(target_dir);
+    let err = match apply_delta(base_dir, changes_dir, &assembly_dir, removed_files, limits){
+        Ok(assembled) if assembled == *manifest => match move_into_place(&assembly_dir, target_dir){
+            Ok(()) => return Ok(()),
+            Err(e) => format!("Couldn't move assembled template into place: {}", e)
+        },
+        Ok(_) => "Assembled template doesn't match the manifest of the new version.".to_string(),
+        Err(e) => format!("IO Error assembling template: {}", e)
+    };
+
+    let _ = fs::remove_dir_all(&assembly_dir);
+    Err(err)
+}
+
+/// Copies the base version to assembly_dir, removes the deleted files & adds the changed ones, returning the manifest of the result
+fn apply_delta(base_dir: &Path, changes_dir: &Path, assembly_dir: &Path, removed_files: &[PathBuf], limits: &TreeLimits) -> io::Result<HashMap<String, String>>{
+    copy_dir_all(base_dir, assembly_dir)?;
+    for file in removed_files{
+        let path = assembly_dir.join(file);
+        if path.is_file(){
+            fs::remove_file(path)?;
+        }
+    }
+    if changes_dir.exists(){
+        // Refuses symlinks in the changed files
+        copy_dir_all(changes_dir, assembly_dir)?;
+    }
+    if let Err(e) = limits::check_tree(assembly_dir, limits){
+        return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string()));
+    }
+    compute_manifest(assembly_dir)
+}
+
+/// New directory next to a template version to write the version into before it's complete
+///
+/// Unique per call, so connections receiving the same version don't write into each other's files.
+pub fn staging_dir(template_dir: &Path) -> PathBuf{
+    template_dir.with_extension(format!("staging-{}", uuid::Uuid::new_v4()))
+}
+
+/// Moves a completely written version from its staging dir to its template dir
+///
+/// Versions are only ever moved into place complete, so if the template dir already exists it's kept & the staging dir removed.
+pub fn move_into_place(staging_dir: &Path, template_dir: &Path) -> io::Result<()>{
+    let res = match fs::rename(staging_dir, template_dir){
+        Err(_) if template_dir.is_dir() => Ok(()),
+        res => res
+    };
+    if staging_dir.exists(){
+        let _ = fs::remove_dir_all(staging_dir);
+    }
+    res
+}
+
+/// Registers a saved template version as the current one
+///
+/// Keeps at most `max_cached_template_versions` versions per template on disk, removing the oldest ones
+/// unless queued or running rendering requests still use them.
+pub fn store_version(storage: &Storage, settings: &Settings, namespace: &str, template_id: uuid::Uuid, template_version_id: uuid::Uuid, export_formats: HashMap<String, ExportFormat>, manifest: HashMap<String, String>){
+    let in_use = versions_in_use(storage, namespace, template_id);
+    let mut outdated_versions = Vec::new();
+
+    {
+        let mut template_storage = storage.template_storage.write().unwrap();
+        let mut cached_versions = template_storage.remove(&(namespace.to_string(), template_id)).map(|entry| entry.cached_versions).unwrap_or_default();
+
+        cached_versions.retain(|version| version.manifest.template_version_id != template_version_id);
+        cached_versions.push(CachedVersion{
+            manifest: TemplateVersionManifest{ template_version_id, files: manifest },
+            export_formats,
+        });
+        let mut excess = cached_versions.len().saturating_sub(settings.max_cached_template_versions.max(1));
+        cached_versions.retain(|version| {
+            let version_id = version.manifest.template_version_id;
+            if excess == 0 || version_id == template_version_id || in_use.contains(&version_id){
+                return true;
+            }
+            excess -= 1;
+            outdated_versions.push(version_id);
+            false
+        });
+
+        template_storage.insert((namespace.to_string(), template_id), TemplateStorageEntry{
+            version_id: template_version_id,
+            cached_versions,
+        });
+    }
+
+    for version_id in outdated_versions{
+        if let Err(e) = fs::remove_dir_all(template_dir(settings, namespace, version_id)){
+            warn!(template_version_id = %version_id, "Couldn't delete outdated template version: {}", e);
+        }
+    }
+}
+
+/// Versions of a template needed by queued or running rendering requests
+fn versions_in_use(storage: &Storage, namespace: &str, template_id: uuid::Uuid) -> Vec<uuid::Uuid>{
+    let mut in_use: Vec<uuid::Uuid> = storage.request_queue.read().unwrap().iter()
+        .filter(|queued| queued.client.template_namespace == namespace && queued.request.template_id == template_id)
+        .map(|queued| queued.request.template_version_id).collect();
+    in_use.extend(storage.running_requests.read().unwrap().values()
+        .filter(|running| running.template_namespace == namespace && running.template_id == template_id)
+        .map(|running| running.template_version_id));
+    in_use
+}
+
+/// Template version found in the temp template dir
+pub struct VersionOnDisk{
+    pub namespace: String,
+    pub template_version_id: String,
+    pub bytes: u64,
+}
+
+/// Lists all template versions saved in the temp template dir, sorted by namespace
+pub fn versions_on_disk(settings: &Settings) -> io::Result<Vec<VersionOnDisk>>{
+    let mut versions = Vec::new();
+    for namespace in fs::read_dir(&settings.temp_template_path)?{
+        let namespace = namespace?;
+        if !namespace.file_type()?.is_dir(){
+            continue;
+        }
+        for version in fs::read_dir(namespace.path())?{
+            let version = version?;
+            versions.push(VersionOnDisk{
+                namespace: namespace.file_name().to_string_lossy().to_string(),
+                template_version_id: version.file_name().to_string_lossy().to_string(),
+                bytes: disk_usage(&version.path())?,
+            });
+        }
+    }
+    versions.sort_by(|a, b| (&a.namespace, &a.template_version_id).cmp(&(&b.namespace, &b.template_version_id)));
+    Ok(versions)
+}
+
+/// Removes all saved template versions, or only those of one namespace, returns the number of removed versions
+///
+/// Callers hold the working dir lock, a running server would still consider the versions cached.
+pub fn purge(settings: &Settings, namespace: Option<&str>) -> io::Result<usize>{
+    let mut removed = 0;
+    for version in versions_on_disk(settings)?{
+        if namespace.is_some_and(|namespace| namespace != version.namespace){
+            continue;
+        }
+        fs::remove_dir_all(template_dir_on_disk(settings, &version))?;
+        removed += 1;
+    }
+    Ok(removed)
+}
+
+fn template_dir_on_disk(settings: &Settings, version: &VersionOnDisk) -> PathBuf{
+    PathBuf::from(&settings.temp_template_path).join(&version.namespace).join(&version.template_version_id)
+}
+
+fn disk_usage(path: &Path) -> io::Result<u64>{
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir(){
+        return Ok(meta.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)?{
+        total += disk_usage(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Removes cached templates from storage & disk, all or only those of one namespace, returns the number of removed templates
+///
+/// Templates of queued or running rendering requests are kept, as they are still needed.
+pub fn evict(storage: &Storage, settings: &Settings, namespace: Option<&str>) -> usize{
+    let mut in_use: Vec<(String, uuid::Uuid)> = storage.request_queue.read().unwrap().iter()
+        .map(|queued| (queued.client.template_namespace.clone(), queued.request.template_id)).collect();
+    in_use.extend(storage.running_requests.read().unwrap().values().map(|running| (running.template_namespace.clone(), running.template_id)));
+
+    let evicted: Vec<((String, uuid::Uuid), TemplateStorageEntry)> = {
+        let mut template_storage = storage.template_storage.write().unwrap();
+        let keys: Vec<(String, uuid::Uuid)> = template_storage.keys()
+            .filter(|key| namespace.is_none_or(|namespace| key.0 == namespace) && !in_use.contains(key))
+            .cloned().collect();
+        keys.into_iter().filter_map(|key| template_storage.remove(&key).map(|entry| (key, entry))).collect()
+    };
+
+    for ((namespace, _), entry) in &evicted{
+        for version in &entry.cached_versions{
+            let version_id = version.manifest.template_version_id;
+            if let Err(e) = fs::remove_dir_all(template_dir(settings, namespace, version_id)){
+                warn!(template_version_id = %version_id, "Couldn't delete evicted template version: {}", e);
+            }
+        }
+    }
+
+    evicted.len()
+}
+
+#[cfg(test)]
+mod tests{
+    use std::sync::Arc;
+    use std::time::Instant;
+    use crate::storage::RunningRequest;
+    use super::*;
+
+    fn temp_dir() -> PathBuf{
+        std::env::temp_dir().join(format!("vb-template-cache-test-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn write_files(dir: &Path, files: &[(&str, &str)]){
+        fs::create_dir_all(dir).unwrap();
+        for (path, content) in files{
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+    }
+
+    fn manifest_of(files: &[(&str, &str)]) -> HashMap<String, String>{
+        files.iter().map(|(path, content)| (path.to_string(), format!("{:x}", Sha256::digest(content.as_bytes())))).collect()
+    }
+
+    fn limits() -> TreeLimits{
+        TreeLimits{ max_total_bytes: 1000, max_files: 10, max_depth: 3, max_file_bytes: 100 }
+    }
+
+    const BASE: &[(&str, &str)] = &[("index.hbs.html", "old"), ("assets/style.css", "css"), ("removed.txt", "gone")];
+    const CHANGED: &[(&str, &str)] = &[("index.hbs.html", "new"), ("assets/new.js", "js")];
+    const ASSEMBLED: &[(&str, &str)] = &[("index.hbs.html", "new"), ("assets/style.css", "css"), ("assets/new.js", "js")];
+
+    #[test]
+    fn manifest_uses_slash_separated_relative_paths(){
+        let dir = temp_dir();
+        write_files(&dir, BASE);
+        assert_eq!(compute_manifest(&dir).unwrap(), manifest_of(BASE));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assembles_base_with_changed_and_removed_files(){
+        let root = temp_dir();
+        let (base, staging, target) = (root.join("base"), root.join("staging"), root.join("target"));
+        write_files(&base, BASE);
+        write_files(&staging, CHANGED);
+
+        assert_eq!(assemble_version(&base, &staging, &target, &["removed.txt".into()], &limits(), &manifest_of(ASSEMBLED)), Ok(()));
+        assert_eq!(compute_manifest(&target).unwrap(), manifest_of(ASSEMBLED));
+        // The base stays usable for later deltas
+        assert_eq!(compute_manifest(&base).unwrap(), manifest_of(BASE));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn deltas_without_changed_files_only_remove(){
+        let root = temp_dir();
+        let (base, staging, target) = (root.join("base"), root.join("staging"), root.join("target"));
+        write_files(&base, BASE);
+
+        let expected = manifest_of(&[("index.hbs.html", "old"), ("assets/style.css", "css")]);
+        assert_eq!(assemble_version(&base, &staging, &target, &["removed.txt".into()], &limits(), &expected), Ok(()));
+        assert_eq!(compute_manifest(&target).unwrap(), expected);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn removes_versions_not_matching_the_manifest(){
+        let tampered = manifest_of(&[("index.hbs.html", "tampered"), ("assets/style.css", "css"), ("assets/new.js", "js")]);
+        let missing_file = manifest_of(&[("index.hbs.html", "new"), ("assets/style.css", "css"), ("assets/new.js", "js"), ("missing.txt", "")]);
+        let extra_file = manifest_of(&[("index.hbs.html", "new"), ("assets/style.css", "css")]);
+
+        for manifest in [tampered, missing_file, extra_file]{
+            let root = temp_dir();
+            let (base, staging, target) = (root.join("base"), root.join("staging"), root.join("target"));
+            write_files(&base, BASE);
+            write_files(&staging, CHANGED);
+
+            assert!(assemble_version(&base, &staging, &target, &["removed.txt".into()], &limits(), &manifest).is_err());
+            assert!(!target.exists());
+            assert_eq!(fs::read_dir(&root).unwrap().count(), 2);
+
+            fs::remove_dir_all(&root).unwrap();
+        }
+    }
+
+    #[test]
+    fn removes_versions_exceeding_limits(){
+        let root = temp_dir();
+        let (base, staging, target) = (root.join("base"), root.join("staging"), root.join("target"));
+        write_files(&base, BASE);
+        write_files(&staging, CHANGED);
+
+        let limits = TreeLimits{ max_files: 3, ..limits() };
+        assert!(assemble_version(&base, &staging, &target, &["removed.txt".into()], &limits, &manifest_of(ASSEMBLED)).is_err());
+        assert!(!target.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_existing_complete_versions(){
+        let root = temp_dir();
+        let (base, staging, target) = (root.join("base"), root.join("staging"), root.join("target"));
+        write_files(&base, BASE);
+        write_files(&staging, CHANGED);
+        let existing = [ASSEMBLED, &[("placed.txt", "first")]].concat();
+        write_files(&target, &existing);
+
+        assert_eq!(assemble_version(&base, &staging, &target, &["removed.txt".into()], &limits(), &manifest_of(ASSEMBLED)), Ok(()));
+        // The version placed first stays in place
+        assert_eq!(compute_manifest(&target).unwrap(), manifest_of(&existing));
+        assert_eq!(fs::read_dir(&root).unwrap().count(), 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn cached_version_ids(storage: &Storage, template_id: uuid::Uuid) -> Vec<uuid::Uuid>{
+        storage.template_storage.read().unwrap()[&("ns".to_string(), template_id)].cached_versions.iter().map(|version| version.manifest.template_version_id).collect()
+    }
+
+    fn running(template_id: uuid::Uuid, template_version_id: uuid::Uuid) -> Arc<RunningRequest>{
+        Arc::new(RunningRequest{
+            client: "client".to_string(),
+            template_namespace: "ns".to_string(),
+            template_id,
+            template_version_id,
+            export_formats: vec![],
+            started: Instant::now(),
+            current_steps: Default::default(),
+            cancelled: Default::default(),
+        })
+    }
+
+    #[test]
+    fn keeps_outdated_versions_while_in_use(){
+        let storage = Storage::new();
+        let mut settings = Settings::new(None).unwrap();
+        settings.temp_template_path = temp_dir().to_string_lossy().to_string();
+        settings.max_cached_template_versions = 1;
+        let template_id = uuid::Uuid::new_v4();
+        let versions: Vec<uuid::Uuid> = (0..3).map(|_| uuid::Uuid::new_v4()).collect();
+
+        store_version(&storage, &settings, "ns", template_id, versions[0], HashMap::new(), HashMap::new());
+        storage.running_requests.write().unwrap().insert(uuid::Uuid::new_v4(), running(template_id, versions[0]));
+        store_version(&storage, &settings, "ns", template_id, versions[1], HashMap::new(), HashMap::new());
+        assert_eq!(cached_version_ids(&storage, template_id), vec![versions[0], versions[1]]);
+        assert!(storage.template_storage.read().unwrap()[&("ns".to_string(), template_id)].export_formats(versions[0]).is_some());
+
+        storage.running_requests.write().unwrap().clear();
+        store_version(&storage, &settings, "ns", template_id, versions[2], HashMap::new(), HashMap::new());
+        assert_eq!(cached_version_ids(&storage, template_id), vec![versions[2]]);
+    }
+}
@@ -0,0 +1,160 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::pki_types::{CertificateDer, CertificateRevocationListDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::version::TLS13;
+use tracing::{error, info};
+use crate::settings::Settings;
+
+/// Current TLS configs, replaced as a whole when certificates or the CRL are reloaded
+///
+/// Connections take the config valid at their start, established connections aren't affected by a reload.
+pub struct TlsConfigs{
+    server: RwLock<Arc<ServerConfig>>,
+    client: RwLock<Arc<ClientConfig>>,
+}
+
+impl TlsConfigs{
+    pub fn load(settings: &Settings) -> Result<TlsConfigs, String>{
+        let (server, client) = build_configs(settings)?;
+
+        Ok(TlsConfigs{
+            server: RwLock::new(Arc::new(server)),
+            client: RwLock::new(Arc::new(client)),
+        })
+    }
+
+    /// Config for incoming connections from main servers
+    pub fn server_config(&self) -> Arc<ServerConfig>{
+        self.server.read().unwrap().clone()
+    }
+
+    /// Config for connections to a main server (reverse-connect mode)
+    pub fn client_config(&self) -> Arc<ClientConfig>{
+        self.client.read().unwrap().clone()
+    }
+
+    /// Loads certificates, key & CRL again and swaps the configs. Keeps the old configs if the new files are invalid
+    pub fn reload(&self, settings: &Settings) -> Result<(), String>{
+        let (server, client) = build_configs(settings)?;
+
+        *self.server.write().unwrap() = Arc::new(server);
+        *self.client.write().unwrap() = Arc::new(client);
+
+        Ok(())
+    }
+}
+
+fn build_configs(settings: &Settings) -> Result<(ServerConfig, ClientConfig), String>{
+    let root_ca = Arc::new(load_root_ca(&settings.ca_cert_path)?);
+    let certs = load_certs(&settings.client_cert_path)?;
+    let key = load_private_key(&settings.client_key_path)?;
+    let crls = load_crls(&settings.revocation_list_path)?;
+
+    let client_verifier = WebPkiClientVerifier::builder(root_ca.clone()).with_crls(crls.clone()).build()
+        .map_err(|e| format!("Couldn't build client verifier: {}", e))?;
+    let server_config = ServerConfig::builder_with_protocol_versions(&[&TLS13])
+        .with_client_cert_verifier(client_verifier)
+        .with_single_cert(certs.clone(), key.clone_key())
+        .map_err(|e| format!("Couldn't build server config: {}", e))?;
+
+    let server_verifier = WebPkiServerVerifier::builder(root_ca).with_crls(crls).build()
+        .map_err(|e| format!("Couldn't build server verifier: {}", e))?;
+    let client_config = ClientConfig::builder_with_protocol_versions(&[&TLS13])
+        .with_webpki_verifier(server_verifier)
+        .with_client_auth_cert(certs, key)
+        .map_err(|e| format!("Couldn't build client config: {}", e))?;
+
+    Ok((server_config, client_config))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, String>{
+    File::open(path).map(BufReader::new).map_err(|e| format!("Couldn't open {}: {}", path, e))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String>{
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("Couldn't parse certificates in {}: {}", path, e))?;
+
+    if certs.is_empty(){
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+fn load_root_ca(path: &str) -> Result<RootCertStore, String>{
+    let mut store = RootCertStore::empty();
+    for cert in load_certs(path)?{
+        store.add(cert).map_err(|e| format!("Invalid CA certificate in {}: {}", path, e))?;
+    }
+    Ok(store)
+}
+
+fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, String>{
+    match rustls_pemfile::private_key(&mut open(path)?){
+        Ok(Some(key)) => Ok(key),
+        Ok(None) => Err(format!("No private key found in {}", path)),
+        Err(e) => Err(format!("Couldn't parse private key in {}: {}", path, e))
+    }
+}
+
+/// Loads the CRLs from a DER or PEM file
+fn load_crls(path: &str) -> Result<Vec<CertificateRevocationListDer<'static>>, String>{
+    let content = std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+
+    if content.starts_with(b"-----BEGIN"){
+        rustls_pemfile::crls(&mut content.as_slice()).collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("Couldn't parse CRL in {}: {}", path, e))
+    }else{
+        Ok(vec![CertificateRevocationListDer::from(content)])
+    }
+}
+
+/// Reloads the TLS configs on SIGHUP and, if tls_reload_interval isn't 0, when a certificate, key or CRL file changed
+pub async fn reload_on_change(configs: Arc<TlsConfigs>, settings: Arc<Settings>){
+    // Registered right after startup, so failing exits like the other startup failures
+    let mut sighup = match signal(SignalKind::hangup()){
+        Ok(sighup) => sighup,
+        Err(e) => {
+            error!("Couldn't register SIGHUP handler: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let mut last_modified = modification_times(&settings);
+
+    loop{
+        if !settings.tls_reload_interval.is_zero(){
+            tokio::select!{
+                _ = sighup.recv() => info!("Received SIGHUP, reloading certificates & CRL."),
+                _ = tokio::time::sleep(settings.tls_reload_interval) => {
+                    if modification_times(&settings) == last_modified{
+                        continue;
+                    }
+                    info!("Certificate files changed, reloading certificates & CRL.");
+                }
+            }
+        }else{
+            sighup.recv().await;
+            info!("Received SIGHUP, reloading certificates & CRL.");
+        }
+
+        match configs.reload(&settings){
+            Ok(()) => {
+                last_modified = modification_times(&settings);
+                info!("Reloaded certificates & CRL.");
+            },
+            Err(e) => error!("Couldn't reload certificates, keeping the old ones: {}", e)
+        }
+    }
+}
+
+fn modification_times(settings: &Settings) -> Vec<Option<SystemTime>>{
+    [&settings.ca_cert_path, &settings.client_cert_path, &settings.client_key_path, &settings.revocation_list_path].iter()
+        .map(|path| std::fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
@@ -0,0 +1,100 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+use crate::settings::Settings;
+use crate::storage::Storage;
+
+/// Directory of a job that is removed as soon as it's dropped, on success, error or panic alike
+pub struct Workspace{
+    path: PathBuf,
+}
+
+impl Workspace{
+    /// Creates a new empty directory inside the job work dir
+    pub fn create(settings: &Settings) -> io::Result<Workspace>{
+        let path = Path::new(&settings.job_work_path).join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir(&path)?;
+        Ok(Workspace{ path })
+    }
+
+    /// Takes ownership of an existing directory, e.g. the saved uploads of a rendering request
+    pub fn adopt(path: PathBuf) -> Workspace{
+        Workspace{ path }
+    }
+
+    pub fn path(&self) -> &PathBuf{
+        &self.path
+    }
+}
+
+impl Drop for Workspace{
+    fn drop(&mut self){
+        if let Err(e) = fs::remove_dir_all(&self.path){
+            if e.kind() != io::ErrorKind::NotFound{
+                warn!(path = %self.path.to_string_lossy(), "Couldn't delete workspace, leaving it to the janitor: {}", e);
+            }
+        }
+    }
+}
+
+/// Periodically removes entries of the job work & upload dirs older than orphan_max_age seconds
+/// and retained workspaces older than failed_workspace_retention seconds
+///
+/// Catches everything left behind by crashed or killed renderers. Entries are judged by their modification time.
+pub async fn run_janitor(storage: Arc<Storage>, settings: Arc<Settings>){
+    let interval = settings.janitor_interval;
+    let max_age = settings.orphan_max_age;
+    let retention = settings.failed_workspace_retention;
+
+    loop{
+        tokio::time::sleep(interval).await;
+        if storage.is_draining(){
+            // Leftovers are removed after draining anyway
+            return;
+        }
+
+        let settings = settings.clone();
+        let res = tokio::task::spawn_blocking(move || -> io::Result<usize>{
+            Ok(sweep(Path::new(&settings.job_work_path), max_age)?
+                + sweep(Path::new(&settings.upload_path), max_age)?
+                + sweep(Path::new(&settings.retained_workspace_path), retention)?)
+        }).await;
+
+        match res{
+            Ok(Ok(0)) => {},
+            Ok(Ok(removed)) => info!("Janitor removed {} orphaned or expired job directories.", removed),
+            Ok(Err(e)) => error!("Janitor couldn't sweep job directories: {}", e),
+            Err(e) => error!("Janitor couldn't sweep job directories: {}", e)
+        }
+    }
+}
+
+/// Removes all entries of a directory last modified longer than max_age ago, returns the number of removed entries
+fn sweep(dir: &Path, max_age: Duration) -> io::Result<usize>{
+    let now = SystemTime::now();
+    let mut removed = 0;
+
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        let age = now.duration_since(meta.modified()?).unwrap_or_default();
+        if age < max_age{
+            continue;
+        }
+
+        let res = if meta.is_dir(){
+            fs::remove_dir_all(entry.path())
+        }else{
+            fs::remove_file(entry.path())
+        };
+        match res{
+            Ok(_) => removed += 1,
+            Err(e) => warn!(path = %entry.path().to_string_lossy(), "Janitor couldn't remove directory: {}", e)
+        }
+    }
+
+    Ok(removed)
+}